@@ -0,0 +1,346 @@
+use core::alloc::Layout;
+use core::{ cmp, ptr };
+use super::node::{ Node, NodeHeader, NodeHeaderExt };
+#[cfg(feature = "debug")]
+use super::debug;
+
+// A region handed to the arena is framed by zero tags so that its nodes never
+// coalesce with anything outside it:
+//
+// | - | 0 | node | node | ... | node | 0 | - |
+//
+// Nodes start 8 mod 16 and are multiples of 16 bytes: payloads are 16 aligned.
+
+// Node bytes in front of the caller's block (head tag, debug header) and behind it
+// (red zone, end tag). PREFIX stays 8 mod 16 for alignment.
+#[cfg(not(feature = "debug"))]
+pub(crate) const PREFIX : u64 = 8;
+#[cfg(not(feature = "debug"))]
+pub(crate) const SUFFIX : u64 = 8;
+#[cfg(feature = "debug")]
+pub(crate) const PREFIX : u64 = 8 + debug::HEADER;
+#[cfg(feature = "debug")]
+pub(crate) const SUFFIX : u64 = debug::RED_ZONE + 8;
+
+pub struct Arena {
+    pub orders : [*mut NodeHeader; 64],
+}
+
+// The free lists point into the heap region, owned by whoever owns the arena
+unsafe impl Send for Arena {}
+
+impl Arena {
+
+    //============================================================
+    //
+    //
+    //============================================================
+    pub const fn new() -> Self {
+        Arena { orders: [ptr::null_mut(); 64] }
+    }
+
+    //============================================================
+    // simple classification based on size order
+    // can be optimized
+    //============================================================
+    pub fn calculate_class(size : u64) -> u32 {
+        63 - (size - 16).leading_zeros()
+    }
+
+    //============================================================
+    //
+    //
+    //============================================================
+    pub fn push_node(&mut self, node: &Node) {
+        unsafe
+        {
+            let class = Self::calculate_class(node.size());
+
+            let head = self.orders[class as usize];     // *mut NodeHeader
+            let node = node.buffer;                     // *mut NodeHeader
+
+            if !head.is_null() {
+                node.unbox().next     = head;
+                head.unbox().previous = node;
+            }
+
+            self.orders[class as usize] = node;
+        }
+    }
+
+    //============================================================
+    // first fit in the size's own class (its nodes may be smaller),
+    // else the head of the next non-empty class
+    //============================================================
+    pub fn find_node(&mut self, size: u64) -> Option<Node> {
+        unsafe {
+            let class_min = Self::calculate_class(size) as usize;
+
+            let mut head = self.orders[class_min];
+            while !head.is_null() {
+                let mut node = Node::from(head.cast::<u8>());
+                if node.size() >= size {
+                    self.remove_node(&mut node);
+                    return Some(node);
+                }
+                head = head.unbox().next;
+            }
+
+            let class_add = self.orders.iter().skip(class_min + 1).position(|p| !p.is_null());
+
+            class_add.map(|class_add| self.pop_node_internal(class_min + 1 + class_add))
+        }
+    }
+
+    //============================================================
+    //
+    //
+    //============================================================
+    unsafe fn pop_node_internal(&mut self, class: usize) -> Node {
+
+        let head = self.orders[class];
+        let node = Node::from(head.cast::<u8>());
+
+        if !head.unbox().next.is_null() {
+            head.unbox().next.unbox().previous = ptr::null_mut();
+        }
+
+        self.orders[class] = head.unbox().next;
+
+        node.buffer.unbox().next = ptr::null_mut();
+        node
+    }
+
+    //============================================================
+    //
+    //
+    //============================================================
+    pub unsafe fn remove_node(&mut self, node: &mut Node) {
+
+        let class = Self::calculate_class(node.size());
+
+        if node.buffer.unbox().previous.is_null() {
+            self.pop_node_internal(class as usize);
+            return;
+        }
+
+        if !node.buffer.unbox().next.is_null() {
+            node.buffer.unbox().next.unbox().previous = node.buffer.unbox().previous;
+        }
+
+        node.buffer.unbox().previous.unbox().next = node.buffer.unbox().next;
+
+        node.buffer.unbox().previous = ptr::null_mut();
+        node.buffer.unbox().next = ptr::null_mut();
+    }
+
+    //============================================================
+    // free nodes in a size class
+    //
+    //============================================================
+    pub fn list_length(&self, class: usize) -> u64 {
+        unsafe {
+            let mut length = 0;
+            let mut node = self.orders[class];
+            while !node.is_null() {
+                length += 1;
+                node = node.unbox().next;
+            }
+            length
+        }
+    }
+
+    //============================================================
+    // hand [start, end) over to the arena as one free node
+    //
+    //============================================================
+    pub unsafe fn add_region(&mut self, start: u64, end: u64) {
+
+        let first = Self::first_node(start);
+        let last  = Self::end_tag(end);
+        assert!(last >= first + 32, "heap region {:#x}..{:#x} too small", start, end);
+
+        ptr::write((first - 8) as *mut u64, 0);
+        ptr::write(last as *mut u64, 0);
+
+        let mut node = Node::new(first as *mut u8, last - first);
+        node.set_free(false);
+        self.free_node(node.buffer.cast::<u8>());
+    }
+
+    //============================================================
+    // grow a region ending at `end` (as given to `add_region`)
+    // up to `new_end`, [end, new_end) must be usable memory
+    //============================================================
+    pub unsafe fn extend(&mut self, end: u64, new_end: u64) {
+
+        let tag  = Self::end_tag(end);
+        let last = Self::end_tag(new_end);
+        debug_assert!(ptr::read(tag as *const u64) == 0, "{:#x} is not the end of a region", end);
+        assert!(last >= tag + 32, "heap region extension {:#x}..{:#x} too small", end, new_end);
+
+        ptr::write(last as *mut u64, 0);
+
+        let mut node = Node::new(tag as *mut u8, last - tag);     // over the old end tag
+        node.set_free(false);
+        self.free_node(node.buffer.cast::<u8>());
+    }
+
+    //============================================================
+    // null when no free node fits, the caller may add memory
+    // (`search_size` bytes are enough) and retry
+    //============================================================
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+
+        let size = Self::node_size(layout);
+        let node = self.find_node(Self::search_size(layout));
+
+        match node {
+            None => {
+                ptr::null_mut()  // NO SPACE LEFT
+            },
+            Some(mut node) => {
+
+                #[cfg(feature = "debug")]
+                unsafe { debug::check_poison(&node); }
+
+                // a free node of its own: dealloc coalesces it back
+                let padding = Self::padding(node.buffer as u64, layout.align() as u64);
+                if padding > 0 {
+                    let (leading, aligned) = node.split(padding);
+                    self.push_node(&leading);
+                    node = aligned;
+                }
+
+                if node.size() - size > 32 {
+                    let (slice, leftover) = node.split(size);
+                    self.push_node(&leftover);
+                    node = slice;
+                }
+
+                node.set_free(false);
+                unsafe { node.buffer.cast::<u8>().add(PREFIX as usize) }
+            }
+        }
+    }
+
+    //============================================================
+    // give back a block from `allocate`
+    //
+    //============================================================
+    pub unsafe fn deallocate(&mut self, block: *mut u8) {
+        self.free_node(block.sub(PREFIX as usize));
+    }
+
+    //============================================================
+    // resize a block without moving it: shrink by freeing the
+    // tail, grow by absorbing a free successor
+    //============================================================
+    pub unsafe fn reallocate(&mut self, block: *mut u8, layout: Layout) -> bool {
+
+        let size = Self::node_size(layout);
+        let allocation_ptr = block.sub(PREFIX as usize);
+        let mut allocation = Node::from(allocation_ptr);
+
+        if size > allocation.size() {
+            let mut next = match allocation.next() {
+                Some(next) if next.is_free() && allocation.size() + next.size() >= size => next,
+                _ => return false,
+            };
+
+            self.remove_node(&mut next);
+            allocation.set_size(allocation.size() + next.size());
+        }
+
+        if allocation.size() - size > 32 {
+            let tail = Node::new(allocation_ptr.add(size as usize), allocation.size() - size);
+            allocation.set_size(size);
+            self.free_node(tail.buffer.cast::<u8>());     // coalesces with a free successor
+        }
+        true
+    }
+
+    //============================================================
+    // node size to look for: room for a leading padding node too
+    // when the payload needs more than 16 bytes alignment
+    //============================================================
+    pub fn search_size(layout: Layout) -> u64 {
+        match layout.align() as u64 {
+            align if align > 16 => Self::node_size(layout) + align + 32,
+            _                   => Self::node_size(layout),
+        }
+    }
+
+    //============================================================
+    //
+    //
+    //============================================================
+    fn node_size(layout: Layout) -> u64 {
+        cmp::max((layout.size() as u64 + PREFIX + SUFFIX + 15) & !15, 32)     // tags stay 8 mod 16: payloads 16 aligned
+    }
+
+    //============================================================
+    // bytes to split off the front of a node so that its payload
+    // is aligned, 0 or at least a minimum node
+    //============================================================
+    fn padding(node: u64, align: u64) -> u64 {
+
+        let payload = node + PREFIX;
+        let padding = ((payload + align - 1) & !(align - 1)) - payload;
+
+        match padding {
+            0 | 32..=u64::MAX => padding,
+            _                 => padding + align,     // align >= 32 here
+        }
+    }
+
+    //============================================================
+    // mark a busy node free, coalesce it with its free neighbours
+    //
+    //============================================================
+    unsafe fn free_node(&mut self, allocation_ptr: *mut u8) {
+
+        let mut allocation = Node::from(allocation_ptr);
+        allocation.set_free(true);
+
+        #[cfg(feature = "debug")]
+        debug::poison(allocation_ptr as u64 + 8, allocation_ptr as u64 + allocation.size() - 8);
+
+        if let Some(mut previous) = allocation.previous() {
+            if previous.is_free() {
+                self.remove_node(&mut previous);
+                allocation = previous.coalesce(allocation);
+
+                #[cfg(feature = "debug")]
+                debug::poison(allocation_ptr as u64 - 8, allocation_ptr as u64 + 8);    // tags of the seam
+            }
+        }
+
+        if let Some(mut next) = allocation.next() {
+            if next.is_free() {
+                #[cfg(feature = "debug")]
+                let seam = next.buffer as u64;
+                self.remove_node(&mut next);
+                allocation = allocation.coalesce(next);
+
+                #[cfg(feature = "debug")]
+                debug::poison(seam - 8, seam + 24);     // tags and links of the seam
+            }
+        }
+
+        // DEBUG - MEMSET 0
+        allocation = Node::new(allocation.buffer.cast::<u8>(), allocation.size());
+
+        self.push_node(&allocation);
+    }
+
+    // first node of a region starting at `start`
+    pub(crate) fn first_node(start: u64) -> u64 {
+        ((start + 15) & !15) + 8
+    }
+
+    // end tag of a region ending at `end`
+    fn end_tag(end: u64) -> u64 {
+        (end & !15) - 8
+    }
+}
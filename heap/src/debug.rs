@@ -0,0 +1,143 @@
+use core::{ mem, ptr, slice };
+use super::arena::PREFIX;
+use super::node::Node;
+
+// HEAP DEBUG MODE (feature "debug")
+//
+// | size | Header | block ... | red zone | size |
+//
+// Free memory is filled with POISON and checked when handed out again, the
+// Header and the red zone behind the block are checked when it is freed.
+// Sites are return addresses collected by the caller.
+//
+// Objects carved by someone else (the kernel's slabs) get the same Header
+// right in front of the block and a red zone up to the end of the object:
+//
+// | ... | Header | block ... | red zone |
+
+pub const POISON     : u8    = 0xDD;
+pub const RED_ZONE   : u64   = 16;      // at least, behind the block
+pub const SITE_DEPTH : usize = 4;       // return addresses kept per block
+
+const POISON_WORD : u64   = 0xDDDD_DDDD_DDDD_DDDD;
+const CANARY      : u64   = 0xC0DE_CAFE_F00D_BEEF;
+const CANARY_BYTE : u8    = 0xCB;
+
+// Between the head tag and the block (16 bytes multiple: blocks stay aligned)
+#[repr(C)]
+struct Header {
+    size   : u64,                   // Layout size
+    site   : [u64; SITE_DEPTH],
+    canary : u64,                   // red zone in front of the block
+}
+
+pub const HEADER : u64 = mem::size_of::<Header>() as u64;
+
+//============================================================
+// record a new block and surround it with red zones
+//
+//============================================================
+pub unsafe fn arm(block: *mut u8, size: usize, site: [u64; SITE_DEPTH]) {
+
+    let node = block.sub(PREFIX as usize);
+    arm_object(block, size, node.add(Node::from(node).size() as usize - 8), site);
+}
+
+//============================================================
+// same for a block ending its object at `end`, the Header
+// fits in front of it
+//============================================================
+pub unsafe fn arm_object(block: *mut u8, size: usize, end: *mut u8, site: [u64; SITE_DEPTH]) {
+
+    let header = block.sub(HEADER as usize).cast::<Header>();
+    ptr::write(header, Header { size: size as u64, site, canary: CANARY });
+
+    let start = block.add(size);
+    ptr::write_bytes(start, CANARY_BYTE, end as usize - start as usize);
+}
+
+//============================================================
+// a busy block being freed or resized: busy bit, Layout size
+// and both red zones
+//============================================================
+pub unsafe fn check(block: *mut u8, size: usize) {
+
+    let node = block.sub(PREFIX as usize);
+    let head = ptr::read(node.cast::<u64>());
+    if head == POISON_WORD || head & 0x1 == 0 {
+        panic!("heap: double free of {:p} (size {:#x})", block, size);
+    }
+
+    check_zones(block, size, node.add(Node::from(node).size() as usize - 8));
+}
+
+//============================================================
+// a block armed by `arm_object` being freed or resized, the
+// poisoned canary of a freed object tells a double free
+//============================================================
+pub unsafe fn check_object(block: *mut u8, size: usize, end: *mut u8, site: [u64; SITE_DEPTH]) {
+
+    let header = &*block.sub(HEADER as usize).cast::<Header>();
+    if header.canary == POISON_WORD {
+        panic!("heap: double free of {:p} (size {:#x}, freed again from {:x?})", block, size, site);
+    }
+
+    check_zones(block, size, end);
+}
+
+// Layout size and both red zones
+unsafe fn check_zones(block: *mut u8, size: usize, end: *mut u8) {
+
+    let header = &*block.sub(HEADER as usize).cast::<Header>();
+
+    let violation = |what: &str| -> ! {
+        panic!("heap: {} at {:p} (size {:#x}, allocated from {:x?})", what, block, header.size, header.site)
+    };
+
+    if header.canary != CANARY {
+        violation("front red zone overwritten");
+    }
+    if header.size != size as u64 {
+        violation(if size as u64 > header.size { "dealloc with a larger Layout" } else { "dealloc with a smaller Layout" });
+    }
+
+    let red_zone = slice::from_raw_parts(block.add(size), end as usize - block as usize - size);
+    if red_zone.iter().any(|&byte| byte != CANARY_BYTE) {
+        violation("write past the end");
+    }
+}
+
+//============================================================
+//
+//
+//============================================================
+pub unsafe fn poison(start: u64, end: u64) {
+    ptr::write_bytes(start as *mut u8, POISON, (end - start) as usize);
+}
+
+//============================================================
+// a free node about to be handed out: nothing wrote to it
+// since it was freed (its list links and tags excepted)
+//============================================================
+pub(crate) unsafe fn check_poison(node: &Node) {
+
+    let start = node.buffer as u64 + 24;
+    let end   = node.buffer as u64 + node.size() - 8;
+
+    let bytes = slice::from_raw_parts(start as *const u8, (end - start) as usize);
+    if let Some(offset) = bytes.iter().position(|&byte| byte != POISON) {
+        panic!("heap: {:#x} written after free (free node {:p}, size {:#x})", start + offset as u64, node.buffer, node.size());
+    }
+}
+
+//============================================================
+// same for a slab object (its free chain link excepted)
+//
+//============================================================
+pub unsafe fn check_poisoned_object(object: *mut u8, size: usize, cache: &str) {
+
+    let bytes = slice::from_raw_parts(object.add(8), size - 8);
+    if let Some(offset) = bytes.iter().position(|&byte| byte != POISON) {
+        panic!("heap: {:p} written after free (object {:p} of cache {})", object.add(8 + offset), object, cache);
+    }
+}
@@ -0,0 +1,15 @@
+#![no_std]
+#![allow(clippy::missing_safety_doc, clippy::new_without_default)]
+
+// Boundary tag allocator over caller provided memory regions. The kernel feeds
+// it the heap segments it maps, the tests feed it plain host buffers.
+
+mod arena;
+mod node;
+mod walk;
+#[cfg(feature = "debug")]
+pub mod debug;
+
+pub use arena::Arena;
+pub use node::{ Node, NodeHeader, NodeHeaderExt };
+pub use walk::{ HeapCorruption, NodeInfo };
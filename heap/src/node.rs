@@ -12,7 +12,6 @@ use core::ptr;
 // |----------|--------------------|
 // | size     | node size          | next node
 
-const NODE_MINIMUM_SIZE : u64 = 32;
 const MINIMUM_NODE_SIZE : u64 = 32;
 
 pub struct NodeHeader {
@@ -100,6 +99,20 @@ impl Node {
         }
     }
 
+    //============================================================
+    // move the end tag, keeping the busy flag (payload untouched)
+    //
+    //============================================================
+    pub unsafe fn set_size(&mut self, size: u64) {
+
+        let sizex = size | (self.buffer.unbox().size & 0x1);
+
+        self.buffer.unbox().size = sizex;
+
+        let node_end = self.buffer.cast::<u8>().add(size as usize - 8);
+        ptr::write(node_end as *mut u64, sizex);
+    }
+
     //============================================================
     //
     //
@@ -0,0 +1,64 @@
+use core::ptr;
+use super::arena::Arena;
+
+#[derive(Debug, Clone, Copy)]
+pub struct NodeInfo {
+    pub address : u64,
+    pub size    : u64,
+    pub free    : bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum HeapCorruption {
+    TagMismatch { address: u64, head: u64, tail: u64 },     // size and end tag differ
+    BadSize { address: u64, size: u64 },                    // unaligned, too small or past the region
+    Uncoalesced { address: u64 },                           // free node right after a free node
+    FreeLists { walked: u64, listed: u64 },                 // free nodes not all in the lists
+}
+
+impl Arena {
+
+    //============================================================
+    // visit the nodes of a region in address order, boundary tags
+    // checked on the way, returns the end of its end tag
+    //============================================================
+    pub unsafe fn walk(start: u64, end: u64, visit: &mut dyn FnMut(&NodeInfo)) -> Result<u64, HeapCorruption> {
+
+        let mut address = Self::first_node(start);
+        let mut previous_free = false;
+
+        loop {
+            let head = ptr::read(address as *const u64);
+            if head == 0 {
+                return Ok(address + 8);     // end tag of the region
+            }
+
+            let size = head & !0x1;
+            if size < 32 || size & 15 != 0 || address + size + 8 > end {
+                return Err(HeapCorruption::BadSize { address, size });
+            }
+
+            let tail = ptr::read((address + size - 8) as *const u64);
+            if tail != head {
+                return Err(HeapCorruption::TagMismatch { address, head, tail });
+            }
+
+            let free = head & 0x1 == 0;
+            if free && previous_free {
+                return Err(HeapCorruption::Uncoalesced { address });
+            }
+
+            previous_free = free;
+            visit(&NodeInfo { address, size, free });
+            address += size;
+        }
+    }
+
+    //============================================================
+    // free nodes in all the lists, the regions walked must
+    // add up to this
+    //============================================================
+    pub fn free_nodes(&self) -> u64 {
+        (0..self.orders.len()).map(|class| self.list_length(class)).sum()
+    }
+}
@@ -0,0 +1,382 @@
+use std::alloc::Layout;
+use std::env;
+use myos_heap::{ Arena, HeapCorruption, NodeInfo };
+
+// Randomized alloc/free/realloc sequences over host buffers, the whole arena is
+// checked after every step. A failing run prints its seed, replay it with
+//
+//     HEAP_SEED=<seed> cargo test
+//
+// and fuzz longer with HEAP_SEEDS=<count> (with and without --features debug).
+
+const SEEDS : u64   = 32;
+const STEPS : usize = 2000;
+
+// Buffers given to the arena: the first one in two steps to go through `extend`
+const REGIONS : [usize; 2] = [0x4_0000, 0x1_0000];
+
+struct Block {
+    address : *mut u8,
+    layout  : Layout,
+    fill    : u8,
+}
+
+struct Heap {
+    seed    : u64,
+    random  : u64,
+    arena   : Arena,
+    regions : Vec<(u64, u64)>,
+    blocks  : Vec<Block>,
+    _memory : Vec<Vec<u128>>,      // u128: 16 aligned
+}
+
+impl Heap {
+
+    //============================================================
+    //
+    //
+    //============================================================
+    fn new(seed: u64) -> Heap {
+
+        let mut heap = Heap { seed, random: seed | 1, arena: Arena::new(), regions: Vec::new(), blocks: Vec::new(), _memory: Vec::new() };
+
+        for (index, &size) in REGIONS.iter().enumerate() {
+            let mut memory = vec![0u128; size / 16];
+            let start = memory.as_mut_ptr() as u64 + 8 * index as u64;     // unaligned start too
+            let end   = memory.as_mut_ptr() as u64 + size as u64;
+
+            unsafe {
+                match index {
+                    0 => {
+                        let middle = start + (size / 2) as u64;
+                        heap.arena.add_region(start, middle);
+                        heap.arena.extend(middle, end);
+                    }
+                    _ => heap.arena.add_region(start, end),
+                }
+            }
+
+            heap.regions.push((start, end));
+            heap._memory.push(memory);
+        }
+        heap.check();
+        heap
+    }
+
+    // xorshift64
+    fn random(&mut self, limit: u64) -> u64 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        self.random % limit
+    }
+
+    fn fail(&self, what: String) -> ! {
+        panic!("seed {}: {}", self.seed, what)
+    }
+
+    //============================================================
+    // mostly small blocks, some large ones, any alignment
+    //
+    //============================================================
+    fn random_layout(&mut self) -> Layout {
+
+        let size = match self.random(16) {
+            0      => 1 + self.random(0x8000),
+            1..=4  => 1 + self.random(0x1000),
+            _      => 1 + self.random(0x200),
+        };
+        let align = 1 << self.random(13);
+
+        Layout::from_size_align(size as usize, align).unwrap()
+    }
+
+    //============================================================
+    //
+    //
+    //============================================================
+    fn allocate(&mut self) {
+
+        let layout  = self.random_layout();
+        let address = self.arena.allocate(layout);
+        if address.is_null() {
+            return;     // full, or too fragmented
+        }
+
+        if address as usize & (layout.align() - 1) != 0 {
+            self.fail(format!("{:p} misaligned for {:?}", address, layout));
+        }
+
+        let fill = self.random(256) as u8;
+        self.push(address, layout, fill);
+    }
+
+    // a new block to keep track of
+    fn push(&mut self, address: *mut u8, layout: Layout, fill: u8) {
+
+        #[cfg(feature = "debug")]
+        unsafe { myos_heap::debug::arm(address, layout.size(), [self.seed, 0, 0, 0]); }
+
+        unsafe { address.write_bytes(fill, layout.size()); }
+        self.blocks.push(Block { address, layout, fill });
+    }
+
+    //============================================================
+    //
+    //
+    //============================================================
+    fn deallocate(&mut self) {
+
+        if self.blocks.is_empty() {
+            return;
+        }
+        let index = self.random(self.blocks.len() as u64) as usize;
+        let block = self.blocks.swap_remove(index);
+        self.verify(&block);
+
+        #[cfg(feature = "debug")]
+        unsafe { myos_heap::debug::check(block.address, block.layout.size()); }
+
+        unsafe { self.arena.deallocate(block.address); }
+    }
+
+    //============================================================
+    // in place or not at all: the contents must survive either way
+    //
+    //============================================================
+    fn reallocate(&mut self) {
+
+        if self.blocks.is_empty() {
+            return;
+        }
+        let index  = self.random(self.blocks.len() as u64) as usize;
+        let size   = 1 + self.random(2 * self.blocks[index].layout.size() as u64 + 64) as usize;
+        let block  = &self.blocks[index];
+        let layout = Layout::from_size_align(size, block.layout.align()).unwrap();
+        self.verify(block);
+
+        #[cfg(feature = "debug")]
+        unsafe { myos_heap::debug::check(block.address, block.layout.size()); }
+
+        if unsafe { self.arena.reallocate(block.address, layout) } {
+            let block = &mut self.blocks[index];
+            let old   = block.layout.size();
+
+            #[cfg(feature = "debug")]
+            unsafe { myos_heap::debug::arm(block.address, size, [0; 4]); }
+
+            if size > old {
+                unsafe { block.address.add(old).write_bytes(block.fill, size - old); }
+            }
+            block.layout = layout;
+        }
+        self.verify(&self.blocks[index]);
+    }
+
+    //============================================================
+    // boundary tags, coalescing, free lists, every block in a busy
+    // node of its own
+    //============================================================
+    fn check(&self) {
+
+        let mut nodes = Vec::new();
+
+        for &(start, end) in self.regions.iter() {
+            let walked = unsafe { Arena::walk(start, end, &mut |node| nodes.push(*node)) };
+            match walked {
+                Ok(last) if last == end & !15 => {}
+                Ok(last) => self.fail(format!("region {:#x}..{:#x} ends at {:#x}", start, end, last)),
+                Err(corruption) => self.fail(format!("{:x?}", corruption)),
+            }
+        }
+
+        let free = nodes.iter().filter(|node| node.free).count() as u64;
+        if free != self.arena.free_nodes() {
+            self.fail(format!("{} free nodes walked, {} in the lists", free, self.arena.free_nodes()));
+        }
+
+        let mut busy: Vec<&NodeInfo> = nodes.iter().filter(|node| !node.free).collect();
+        busy.sort_by_key(|node| node.address);
+        let mut blocks: Vec<&Block> = self.blocks.iter().collect();
+        blocks.sort_by_key(|block| block.address as u64);
+
+        if busy.len() != blocks.len() {
+            self.fail(format!("{} busy nodes for {} blocks", busy.len(), blocks.len()));
+        }
+
+        // both in address order, the nodes don't overlap (walked, regions are disjoint)
+        for (node, block) in busy.iter().zip(blocks.iter()) {
+            let (start, end) = (block.address as u64, block.address as u64 + block.layout.size() as u64);
+            if start < node.address + 8 || end > node.address + node.size - 8 {
+                self.fail(format!("block {:#x}..{:#x} outside its node {:x?}", start, end, node));
+            }
+
+        }
+    }
+
+    // contents as last written, checked before a block is freed or resized
+    fn verify(&self, block: &Block) {
+
+        let bytes = unsafe { std::slice::from_raw_parts(block.address, block.layout.size()) };
+        if let Some(offset) = bytes.iter().position(|&byte| byte != block.fill) {
+            self.fail(format!("block {:p} overwritten at +{:#x}", block.address, offset));
+        }
+    }
+
+    //============================================================
+    // free everything: one free node per region is left
+    //
+    //============================================================
+    fn drain(&mut self) {
+
+        while !self.blocks.is_empty() {
+            self.deallocate();
+        }
+        self.check();
+
+        if self.arena.free_nodes() != self.regions.len() as u64 {
+            self.fail(format!("{} free nodes left in {} regions", self.arena.free_nodes(), self.regions.len()));
+        }
+    }
+}
+
+fn seeds() -> Vec<u64> {
+    let variable = |name| env::var(name).ok().map(|value: String| value.parse::<u64>().expect(name));
+
+    match (variable("HEAP_SEED"), variable("HEAP_SEEDS")) {
+        (Some(seed), _) => vec![seed],
+        (None, count)   => (1..=count.unwrap_or(SEEDS)).map(|seed| seed.wrapping_mul(0x9E37_79B9_7F4A_7C15)).collect(),
+    }
+}
+
+#[test]
+fn random_sequences() {
+    for seed in seeds() {
+        let mut heap = Heap::new(seed);
+
+        for _ in 0..STEPS {
+            match heap.random(8) {
+                0..=3 => heap.allocate(),
+                4..=5 => heap.deallocate(),
+                _     => heap.reallocate(),
+            }
+            heap.check();
+        }
+        heap.drain();
+    }
+}
+
+#[test]
+fn fill_up_and_drain() {
+    for seed in seeds() {
+        let mut heap = Heap::new(seed);
+
+        // until a few allocations in a row fail
+        let mut failures = 0;
+        while failures < 8 {
+            let count = heap.blocks.len();
+            heap.allocate();
+            failures = if heap.blocks.len() == count { failures + 1 } else { 0 };
+        }
+        heap.check();
+
+        // half of them first, scattered
+        let mut index = 0;
+        while index < heap.blocks.len() {
+            let block = heap.blocks.swap_remove(index);
+            heap.verify(&block);
+            unsafe { heap.arena.deallocate(block.address); }
+            index += 1;
+        }
+        heap.check();
+        heap.drain();
+    }
+}
+
+#[test]
+fn every_alignment() {
+    let mut heap = Heap::new(1);
+
+    for shift in 0..=12 {
+        for &size in [1, 16, 100, 4096, 5000].iter() {
+            let layout  = Layout::from_size_align(size, 1 << shift).unwrap();
+            let address = heap.arena.allocate(layout);
+
+            assert!(!address.is_null() && address as usize & (layout.align() - 1) == 0, "{:p} for {:?}", address, layout);
+            heap.push(address, layout, shift as u8);
+        }
+    }
+    heap.check();
+    heap.drain();
+}
+
+#[test]
+fn reallocate_in_place() {
+    let mut heap = Heap::new(1);
+    let layout = |size| Layout::from_size_align(size, 16).unwrap();
+
+    let first  = heap.arena.allocate(layout(100));
+    let second = heap.arena.allocate(layout(100));
+    let third  = heap.arena.allocate(layout(100));
+    unsafe {
+        assert!(!heap.arena.reallocate(first, layout(200)), "grew over a busy node");
+
+        heap.arena.deallocate(second);
+        assert!(heap.arena.reallocate(first, layout(200)), "did not absorb the free successor");
+        assert!(heap.arena.reallocate(first, layout(16)), "did not shrink");
+        assert!(!heap.arena.reallocate(first, layout(0x10_0000)), "grew past the region");
+
+        heap.arena.deallocate(first);
+        heap.arena.deallocate(third);
+    }
+    heap.drain();
+}
+
+#[test]
+fn corruption_is_reported() {
+    let mut heap = Heap::new(1);
+
+    heap.arena.allocate(Layout::from_size_align(64, 16).unwrap());
+
+    let mut busy = None;
+    for &(start, end) in heap.regions.iter() {
+        unsafe { Arena::walk(start, end, &mut |node| if !node.free { busy = Some(*node) }).unwrap(); }
+    }
+
+    let node = busy.unwrap();
+    unsafe { ((node.address + node.size - 8) as *mut u64).write(0x1234_5670); }   // end tag
+
+    let reported = heap.regions.iter().any(|&(start, end)| {
+        matches!(unsafe { Arena::walk(start, end, &mut |_| {}) }, Err(HeapCorruption::TagMismatch { .. }))
+    });
+    assert!(reported, "overwritten end tag not reported");
+}
+
+#[cfg(feature = "debug")]
+#[test]
+#[should_panic(expected = "write past the end")]
+fn overflow_is_caught() {
+    let mut heap = Heap::new(1);
+
+    let address = heap.arena.allocate(Layout::from_size_align(100, 16).unwrap());
+    unsafe {
+        myos_heap::debug::arm(address, 100, [0; 4]);
+        address.add(100).write(0);
+        myos_heap::debug::check(address, 100);
+    }
+}
+
+#[cfg(feature = "debug")]
+#[test]
+#[should_panic(expected = "dealloc with a smaller Layout")]
+fn object_layout_is_checked() {
+    let mut object = vec![0u128; 8];
+    let block = unsafe { (object.as_mut_ptr() as *mut u8).add(myos_heap::debug::HEADER as usize) };
+    let end   = unsafe { (object.as_mut_ptr() as *mut u8).add(128) };
+
+    unsafe {
+        myos_heap::debug::arm_object(block, 40, end, [0; 4]);
+        myos_heap::debug::check_object(block, 40, end, [0; 4]);
+        myos_heap::debug::check_object(block, 32, end, [0; 4]);
+    }
+}
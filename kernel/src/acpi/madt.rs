@@ -0,0 +1,202 @@
+// MULTIPLE APIC DESCRIPTION TABLE
+
+use alloc::vec::Vec;
+use core::{mem, ptr};
+use super::{find_table, physical, SdtHeader};
+
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+
+const ENTRY_LOCAL_APIC:           u8 = 0;
+const ENTRY_IO_APIC:              u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE:   u8 = 2;
+const ENTRY_LOCAL_APIC_NMI:       u8 = 4;
+const ENTRY_LOCAL_APIC_OVERRIDE:  u8 = 5;
+const ENTRY_LOCAL_X2APIC:         u8 = 9;
+
+const FLAG_PCAT_COMPAT: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub processor_id: u32,
+    pub apic_id:      u32,
+    pub enabled:      bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id:       u8,
+    pub address:  u64,
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// ISA IRQ wired to a different GSI, polarity or trigger mode than the identity default
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq:      u8,
+    pub gsi:      u32,
+    pub polarity: Polarity,
+    pub trigger:  TriggerMode,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    pub processor_id: u8,       // 0xff: all processors
+    pub lint:         u8,
+    pub polarity:     Polarity,
+    pub trigger:      TriggerMode,
+}
+
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub legacy_pics:        bool,
+    pub processors:         Vec<Processor>,
+    pub io_apics:           Vec<IoApicInfo>,
+    pub overrides:          Vec<InterruptOverride>,
+    pub nmis:               Vec<LocalApicNmi>,
+}
+
+impl InterruptOverride {
+
+    //============================================================
+    /// ISA default: GSI = IRQ, active high, edge triggered
+    //
+    //============================================================
+    pub const fn identity(irq: u8) -> InterruptOverride {
+        InterruptOverride {
+            irq,
+            gsi:      irq as u32,
+            polarity: Polarity::ActiveHigh,
+            trigger:  TriggerMode::Edge,
+        }
+    }
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn parse() -> Option<Madt> {
+
+    let table  = find_table(MADT_SIGNATURE)?.0;
+    let header = unsafe { ptr::read_unaligned(physical::<SdtHeader>(table)) };
+
+    let header_size = mem::size_of::<SdtHeader>() as u64;
+    let end         = table + header.length as u64;
+
+    if end < table + header_size + 8 {
+        return None;    // no room for the local APIC address and flags
+    }
+
+    let mut madt = Madt {
+        local_apic_address: read::<u32>(table + header_size) as u64,
+        legacy_pics:        read::<u32>(table + header_size + 4) & FLAG_PCAT_COMPAT != 0,
+        processors:         Vec::new(),
+        io_apics:           Vec::new(),
+        overrides:          Vec::new(),
+        nmis:               Vec::new(),
+    };
+
+    let mut entry = table + header_size + 8;
+
+    while entry + 2 <= end {
+
+        let kind   = read::<u8>(entry);
+        let length = read::<u8>(entry + 1) as u64;
+
+        if length < 2 || entry + length > end {
+            break;  // malformed or truncated, nothing after it can be trusted
+        }
+
+        match kind {
+            _ if length < minimum_length(kind) => {}    // too short for its type, skipped
+            ENTRY_LOCAL_APIC => madt.processors.push(Processor {
+                processor_id: read::<u8>(entry + 2) as u32,
+                apic_id:      read::<u8>(entry + 3) as u32,
+                enabled:      read::<u32>(entry + 4) & 0x1 != 0,
+            }),
+            ENTRY_IO_APIC => madt.io_apics.push(IoApicInfo {
+                id:       read::<u8>(entry + 2),
+                address:  read::<u32>(entry + 4) as u64,
+                gsi_base: read::<u32>(entry + 8),
+            }),
+            ENTRY_INTERRUPT_OVERRIDE => {
+                let flags = read::<u16>(entry + 8);
+                madt.overrides.push(InterruptOverride {
+                    irq:      read::<u8>(entry + 3),
+                    gsi:      read::<u32>(entry + 4),
+                    polarity: polarity(flags, Polarity::ActiveHigh),
+                    trigger:  trigger(flags, TriggerMode::Edge),
+                });
+            }
+            ENTRY_LOCAL_APIC_NMI => {
+                let flags = read::<u16>(entry + 3);
+                madt.nmis.push(LocalApicNmi {
+                    processor_id: read::<u8>(entry + 2),
+                    lint:         read::<u8>(entry + 5),
+                    polarity:     polarity(flags, Polarity::ActiveHigh),
+                    trigger:      trigger(flags, TriggerMode::Edge),
+                });
+            }
+            ENTRY_LOCAL_APIC_OVERRIDE => {
+                madt.local_apic_address = read::<u64>(entry + 4);
+            }
+            ENTRY_LOCAL_X2APIC => madt.processors.push(Processor {
+                processor_id: read::<u32>(entry + 12),
+                apic_id:      read::<u32>(entry + 4),
+                enabled:      read::<u32>(entry + 8) & 0x1 != 0,
+            }),
+            _ => {}
+        }
+
+        entry += length;
+    }
+
+    Some(madt)
+}
+
+// Bytes an entry needs for the fields read from it
+fn minimum_length(kind: u8) -> u64 {
+    match kind {
+        ENTRY_LOCAL_APIC          => 8,
+        ENTRY_IO_APIC             => 12,
+        ENTRY_INTERRUPT_OVERRIDE  => 10,
+        ENTRY_LOCAL_APIC_NMI      => 6,
+        ENTRY_LOCAL_APIC_OVERRIDE => 12,
+        ENTRY_LOCAL_X2APIC        => 16,
+        _                         => 2,
+    }
+}
+
+// MPS INTI flags: polarity in bits 0-1, trigger mode in bits 2-3 (0b00: conforms to the bus)
+fn polarity(flags: u16, default: Polarity) -> Polarity {
+    match flags & 0x3 {
+        0b01 => Polarity::ActiveHigh,
+        0b11 => Polarity::ActiveLow,
+        _    => default,
+    }
+}
+
+fn trigger(flags: u16, default: TriggerMode) -> TriggerMode {
+    match (flags >> 2) & 0x3 {
+        0b01 => TriggerMode::Edge,
+        0b11 => TriggerMode::Level,
+        _    => default,
+    }
+}
+
+fn read<T>(address: u64) -> T {
+    unsafe { ptr::read_unaligned(physical::<T>(address)) }
+}
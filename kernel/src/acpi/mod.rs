@@ -0,0 +1,124 @@
+// ACPI TABLE DISCOVERY
+
+pub mod madt;
+
+use core::{mem, ptr, slice};
+use crate::paging::{PhysicalAddress, PHYSICAL_MEMORY_OFFSET};
+
+#[repr(C, packed)]
+struct Rsdp {
+    signature:          [u8; 8],
+    checksum:           u8,
+    oem_id:             [u8; 6],
+    revision:           u8,
+    rsdt_address:       u32,
+    // ACPI 2.0+
+    length:             u32,
+    xsdt_address:       u64,
+    extended_checksum:  u8,
+    reserved:           [u8; 3],
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature:          [u8; 4],
+    pub length:             u32,
+    pub revision:           u8,
+    pub checksum:           u8,
+    pub oem_id:             [u8; 6],
+    pub oem_table_id:       [u8; 8],
+    pub oem_revision:       u32,
+    pub creator_id:         u32,
+    pub creator_revision:   u32,
+}
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// Longest table believed before checksumming it (the MADT of a big machine is a few KiB)
+const MAX_TABLE_LENGTH: usize = 0x10_0000;
+
+//============================================================
+/// Find a system description table by signature (e.g. b"APIC")
+//
+//============================================================
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysicalAddress> {
+
+    let address = find_rsdp()?.0;
+    let rsdp = unsafe { ptr::read_unaligned(physical::<Rsdp>(address)) };
+
+    // the XSDT only when the extended RSDP checks out, the RSDT is covered by the first 20 bytes
+    let extended = rsdp.revision >= 2
+        && (mem::size_of::<Rsdp>()..=MAX_TABLE_LENGTH).contains(&(rsdp.length as usize))
+        && is_valid(address, rsdp.length as usize);
+
+    let (root, entry_size) = match extended {
+        true  => (rsdp.xsdt_address, 8),
+        false => (rsdp.rsdt_address as u64, 4),
+    };
+
+    // a short, oversized or corrupt root table has no entries worth walking
+    let length  = checked_length(root)?;
+    let entries = (length - mem::size_of::<SdtHeader>()) / entry_size;
+    let first   = root + mem::size_of::<SdtHeader>() as u64;
+
+    (0..entries)
+        .map(|i| unsafe {
+            match entry_size {
+                4 => ptr::read_unaligned(physical::<u32>(first + i as u64 * 4)) as u64,
+                _ => ptr::read_unaligned(physical::<u64>(first + i as u64 * 8)),
+            }
+        })
+        .find(|&table| {
+            let header = unsafe { ptr::read_unaligned(physical::<SdtHeader>(table)) };
+            header.signature == *signature && checked_length(table).is_some()
+        })
+        .map(PhysicalAddress)
+}
+
+//============================================================
+/// Scan the first KiB of the EBDA, then the BIOS area (0xE0000-0xFFFFF)
+//
+//============================================================
+fn find_rsdp() -> Option<PhysicalAddress> {
+
+    let ebda = unsafe { ptr::read_unaligned(physical::<u16>(0x40e)) as u64 } << 4;
+
+    let candidates = (ebda..ebda + 1024).step_by(16).chain((0xe0000..0x100000).step_by(16));
+
+    for address in candidates {
+        let signature = unsafe { &*physical::<[u8; 8]>(address) };
+        if signature == RSDP_SIGNATURE && is_valid(address, 20) {
+            return Some(PhysicalAddress(address));
+        }
+    }
+    None
+}
+
+//============================================================
+/// Length of a table whose header is sane and whose bytes
+/// sum to zero
+//============================================================
+fn checked_length(address: u64) -> Option<usize> {
+
+    let header = unsafe { ptr::read_unaligned(physical::<SdtHeader>(address)) };
+    let length = header.length as usize;
+
+    if length < mem::size_of::<SdtHeader>() || length > MAX_TABLE_LENGTH || !is_valid(address, length) {
+        return None;
+    }
+    Some(length)
+}
+
+//============================================================
+/// All bytes of a table sum to zero
+//
+//============================================================
+fn is_valid(address: u64, length: usize) -> bool {
+    let bytes = unsafe { slice::from_raw_parts(physical::<u8>(address), length) };
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+fn physical<T>(address: u64) -> *const T {
+    (PHYSICAL_MEMORY_OFFSET + address) as *const T
+}
@@ -1,12 +1,14 @@
 use core::fmt;
 use lazy_static::lazy_static;
-use spin::Mutex;
 
 use crate::ktty;
+use crate::sync::IrqSpinlock;
+
+const COM1: u16 = 0x3f8;
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        tty0: ktty::Device::new(0x3f8),
+    pub static ref WRITER: IrqSpinlock<Writer> = IrqSpinlock::new(Writer {
+        tty0: ktty::Device::new(COM1),
     });
 }
 
@@ -52,9 +54,31 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Prints without taking `WRITER`, for exception handlers and panics that may
+/// have interrupted its owner. Output can interleave with other CPUs.
+#[macro_export]
+macro_rules! emergency_print {
+    ($($arg:tt)*) => ($crate::console::_emergency_print(format_args!($($arg)*)));
+}
+
+/// `emergency_print!` with a newline.
+#[macro_export]
+macro_rules! emergency_println {
+    () => ($crate::emergency_print!("\n"));
+    ($($arg:tt)*) => ($crate::emergency_print!("{}\n", format_args!($($arg)*)));
+}
+
 /// Prints the given formatted string to the VGA text buffer through the global `WRITER` instance.
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
     WRITER.lock().write_fmt(args).unwrap();
 }
+
+/// Lock-free path: a private writer on the same serial port.
+#[doc(hidden)]
+pub fn _emergency_print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    let mut writer = Writer { tty0: ktty::Device::new(COM1) };
+    let _ = writer.write_fmt(args);
+}
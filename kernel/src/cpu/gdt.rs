@@ -1,4 +1,6 @@
+use core::mem;
 use crate::paging::*;
+use super::tss::{self, TaskStateSegment};
 
 pub static mut PTR: DescriptorTablePointer = DescriptorTablePointer { limit: 0, base: 0 };
 pub static mut GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
@@ -17,8 +19,15 @@ pub struct GlobalDescriptorTable {
     size: usize,
 }
 
-#[derive(Debug)]
-pub struct SegmentSelector(u16);
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SegmentSelector(pub u16);
+
+// Fixed layout: `syscall` expects kernel data right after kernel code,
+// `sysret` expects user code right after user data.
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, 0);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, 0);
+pub const USER_DATA_SELECTOR:   SegmentSelector = SegmentSelector::new(3, 3);
+pub const USER_CODE_SELECTOR:   SegmentSelector = SegmentSelector::new(4, 3);
 
 impl GlobalDescriptorTable {
 
@@ -54,7 +63,7 @@ impl GlobalDescriptorTable {
         }
 
         let rpl = match descriptor {
-            UserSegment(value) => (value & 0x3) >> 45,
+            UserSegment(value) => (value >> 45) & 0x3,
             SystemSegment(_)   => 0,
         };
 
@@ -81,9 +90,14 @@ pub fn init() {
     unsafe {
         let kernel_code = GDT.add_entry(UserSegment(0x00209a0000000000));      // Kernel Code
         let kernel_data = GDT.add_entry(UserSegment(0x0000920000000000));      // Kernel Data
-        let user_code   = GDT.add_entry(UserSegment(0x0020fa0000000000));      // User Code (Ring-3)
         let user_data   = GDT.add_entry(UserSegment(0x0000f20000000000));      // User Data (Ring-3)
-        // GDT.add_entry(SegmentDescriptor(xxxx));                             // TSS (todo)
+        let user_code   = GDT.add_entry(UserSegment(0x0020fa0000000000));      // User Code (Ring-3)
+
+        debug_assert!(kernel_code == KERNEL_CODE_SELECTOR && kernel_data == KERNEL_DATA_SELECTOR);
+        debug_assert!(user_data == USER_DATA_SELECTOR && user_code == USER_CODE_SELECTOR);
+
+        tss::init();
+        let tss         = GDT.add_entry(SegmentDescriptor::tss_segment(&tss::TSS)); // TSS
 
         PTR.limit = (GDT.size * 8 - 1) as u16;
         PTR.base  = GDT.descriptors.as_ptr() as u64;
@@ -95,6 +109,8 @@ pub fn init() {
         load_cs(kernel_code);
         llvm_asm!("movw $0, %ds " :: "r" (kernel_data.0) : "memory");
         llvm_asm!("movw $0, %es " :: "r" (kernel_data.0) : "memory");
+
+        load_tss(tss);
     }
 }
 
@@ -106,9 +122,38 @@ pub unsafe fn load_cs(sel: SegmentSelector) {
           1:" :: "ri" (sel.0 as usize) : "rax" "memory");
 }
 
+pub unsafe fn load_tss(sel: SegmentSelector) {
+    llvm_asm!("ltr $0" :: "r" (sel.0) : "memory");
+}
+
 #[derive(Debug)]
 pub enum SegmentDescriptor {
     UserSegment(u64),       // Code and data segments
     SystemSegment(u128),    // TSS descriptor
 }
 use SegmentDescriptor::*;
+
+impl SegmentDescriptor {
+
+    //============================================================
+    /// Create an available 64-bit TSS descriptor
+    //
+    //============================================================
+    pub fn tss_segment(tss: &'static TaskStateSegment) -> SegmentDescriptor {
+
+        let base  = tss as *const _ as u64;
+        let limit = (mem::size_of::<TaskStateSegment>() - 1) as u64;
+
+        let mut low = 0u64;
+        low |= limit & 0xffff;                      // limit 0..15
+        low |= (base & 0xffffff) << 16;             // base 0..23
+        low |= 0b1001 << 40;                        // type: available 64-bit TSS
+        low |= 1 << 47;                             // present
+        low |= ((limit >> 16) & 0xf) << 48;         // limit 16..19
+        low |= ((base >> 24) & 0xff) << 56;         // base 24..31
+
+        let high = base >> 32;                      // base 32..63
+
+        SystemSegment(((high as u128) << 64) | (low as u128))
+    }
+}
@@ -0,0 +1,27 @@
+// PORT I/O
+
+//============================================================
+//
+//
+//============================================================
+pub unsafe fn outb(port: u16, value: u8) {
+    llvm_asm!("outb $1, $0" :: "N{dx}"(port), "{al}"(value) :: "volatile");
+}
+
+//============================================================
+//
+//
+//============================================================
+pub unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    llvm_asm!("inb $1, $0" : "={al}"(value) : "N{dx}"(port) :: "volatile");
+    value
+}
+
+//============================================================
+/// Give slow devices (e.g. the 8259) time to settle
+//
+//============================================================
+pub unsafe fn io_wait() {
+    outb(0x80, 0);      // POST diagnostic port, unused
+}
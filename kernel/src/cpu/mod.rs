@@ -1,3 +1,211 @@
 pub mod gdt;
+pub mod tss;
+pub mod msr;
+pub mod io;
 
-//pub use allocator::HeapAllocator;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub const MAX_CPUS: usize = 64;      // one bit each in the online mask
+
+#[derive(Clone, Copy)]
+struct InterruptNesting {
+    depth:          u32,
+    enable_on_exit: bool,      // interrupts were enabled when the outermost section started
+}
+
+static ONLINE: AtomicU64 = AtomicU64::new(0);     // bit per CPU id
+
+static mut INTERRUPT_NESTING: [InterruptNesting; MAX_CPUS] = [InterruptNesting { depth: 0, enable_on_exit: false }; MAX_CPUS];
+
+//============================================================
+/// Stop the current CPU for good (interrupts disabled)
+//
+//============================================================
+pub fn halt() -> ! {
+    loop {
+        unsafe { llvm_asm!("cli; hlt" :::: "volatile"); }
+    }
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn enable_interrupts() {
+    unsafe { llvm_asm!("sti" ::: "memory" : "volatile"); }
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn disable_interrupts() {
+    unsafe { llvm_asm!("cli" ::: "memory" : "volatile"); }
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn interrupts_enabled() -> bool {
+    let rflags: u64;
+    unsafe { llvm_asm!("pushfq; popq $0" : "=r"(rflags) ::: "volatile"); }
+    rflags & (1 << 9) != 0
+}
+
+//============================================================
+/// Run a closure with interrupts disabled, restoring the previous state
+//
+//============================================================
+pub fn without_interrupts<F, R>(f: F) -> R where F: FnOnce() -> R {
+    push_interrupts_disabled();
+    let result = f();
+    pop_interrupts_disabled();
+    result
+}
+
+//============================================================
+/// Disable interrupts, nesting with the other disabled sections of this CPU
+//
+//============================================================
+pub fn push_interrupts_disabled() {
+
+    let enabled = interrupts_enabled();
+    disable_interrupts();
+
+    // interrupts are off: nothing else runs on this CPU until the matching pop
+    let nesting = unsafe { &mut INTERRUPT_NESTING[id()] };
+    if nesting.depth == 0 {
+        nesting.enable_on_exit = enabled;
+    }
+    nesting.depth += 1;
+}
+
+//============================================================
+/// Leave a disabled section, interrupts come back with the outermost one
+//
+//============================================================
+pub fn pop_interrupts_disabled() {
+
+    assert!(!interrupts_enabled(), "interrupts enabled inside a disabled section");
+
+    let nesting = unsafe { &mut INTERRUPT_NESTING[id()] };
+    assert!(nesting.depth > 0, "unbalanced pop_interrupts_disabled");
+
+    nesting.depth -= 1;
+    if nesting.depth == 0 && nesting.enable_on_exit {
+        enable_interrupts();
+    }
+}
+
+//============================================================
+/// Index of the current CPU (its local APIC id, 0 before the APIC is up)
+//
+//============================================================
+pub fn id() -> usize {
+    let id = crate::interrupts::apic::id() as usize;
+    assert!(id < MAX_CPUS, "APIC id {} above MAX_CPUS", id);
+    id
+}
+
+//============================================================
+/// Count the current CPU in cross-CPU requests (TLB shootdowns)
+//
+//============================================================
+pub fn set_online() {
+    ONLINE.fetch_or(1 << id(), Ordering::SeqCst);
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn online_mask() -> u64 {
+    ONLINE.load(Ordering::SeqCst)
+}
+
+//============================================================
+/// Sleep until the next interrupt
+//
+//============================================================
+pub fn wait_for_interrupt() {
+    unsafe { llvm_asm!("hlt" :::: "volatile"); }
+}
+
+//============================================================
+/// Read the page fault linear address (CR2)
+//
+//============================================================
+pub fn read_cr2() -> u64 {
+    let cr2: u64;
+    unsafe { llvm_asm!("movq %cr2, $0" : "=r"(cr2) ::: "volatile"); }
+    cr2
+}
+
+//============================================================
+/// Physical address of the active PML4 (CR3 without its flags)
+//
+//============================================================
+pub fn read_cr3() -> u64 {
+    let cr3: u64;
+    unsafe { llvm_asm!("movq %cr3, $0" : "=r"(cr3) ::: "volatile"); }
+    cr3 & 0x000fffff_fffff000
+}
+
+//============================================================
+/// Switch address space (flushes the non-global TLB entries)
+//
+//============================================================
+pub unsafe fn write_cr3(p4: u64) {
+    llvm_asm!("movq $0, %cr3" :: "r"(p4) : "memory" : "volatile");
+}
+
+//============================================================
+/// Returns (eax, ebx, ecx, edx) for the given leaf/subleaf
+//
+//============================================================
+pub fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        llvm_asm!("cpuid"
+            : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+            : "{eax}"(leaf), "{ecx}"(subleaf)
+            :: "volatile");
+    }
+    (eax, ebx, ecx, edx)
+}
+
+//============================================================
+/// Snapshot of the general purpose registers
+//
+// Field order matches the push order of the entry stubs (rax at the lowest address).
+//============================================================
+#[derive(Default, Clone, Copy)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8:  u64,
+    pub r9:  u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+impl fmt::Debug for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}", self.rax, self.rbx, self.rcx, self.rdx)?;
+        writeln!(f, "RSI={:016x} RDI={:016x} RBP={:016x} R8 ={:016x}", self.rsi, self.rdi, self.rbp, self.r8)?;
+        writeln!(f, "R9 ={:016x} R10={:016x} R11={:016x} R12={:016x}", self.r9, self.r10, self.r11, self.r12)?;
+        write!(f,   "R13={:016x} R14={:016x} R15={:016x}", self.r13, self.r14, self.r15)
+    }
+}
@@ -0,0 +1,27 @@
+// MODEL SPECIFIC REGISTERS
+
+pub const IA32_EFER:           u32 = 0xC000_0080;
+pub const IA32_STAR:           u32 = 0xC000_0081;
+pub const IA32_LSTAR:          u32 = 0xC000_0082;
+pub const IA32_FMASK:          u32 = 0xC000_0084;
+pub const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+pub const EFER_SYSCALL_ENABLE: u64 = 1 << 0;
+
+//============================================================
+//
+//
+//============================================================
+pub unsafe fn read(msr: u32) -> u64 {
+    let (high, low): (u32, u32);
+    llvm_asm!("rdmsr" : "={eax}"(low), "={edx}"(high) : "{ecx}"(msr) : "memory" : "volatile");
+    ((high as u64) << 32) | (low as u64)
+}
+
+//============================================================
+//
+//
+//============================================================
+pub unsafe fn write(msr: u32, value: u64) {
+    llvm_asm!("wrmsr" :: "{ecx}"(msr), "{eax}"(value as u32), "{edx}"((value >> 32) as u32) : "memory" : "volatile");
+}
@@ -0,0 +1,90 @@
+use core::mem;
+
+pub const DOUBLE_FAULT_IST_INDEX:  u16 = 0;
+pub const NMI_IST_INDEX:           u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+const STACK_SIZE: usize = 4096 * 5;
+
+#[repr(align(16))]
+struct Stack([u8; STACK_SIZE]);
+
+// Ring 0 stack used on ring 3 -> ring 0 transitions until threads provide their own
+static mut KERNEL_STACK:        Stack = Stack([0; STACK_SIZE]);
+static mut DOUBLE_FAULT_STACK:  Stack = Stack([0; STACK_SIZE]);
+static mut NMI_STACK:           Stack = Stack([0; STACK_SIZE]);
+static mut MACHINE_CHECK_STACK: Stack = Stack([0; STACK_SIZE]);
+
+pub static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct TaskStateSegment {
+    reserved_1:                 u32,
+    pub privilege_stack_table:  [u64; 3],   // RSP0-RSP2
+    reserved_2:                 u64,
+    pub interrupt_stack_table:  [u64; 7],   // IST1-IST7
+    reserved_3:                 u64,
+    reserved_4:                 u16,
+    pub iomap_base:             u16,
+}
+
+impl TaskStateSegment {
+
+    //============================================================
+    //
+    //
+    //============================================================
+    pub const fn new() -> TaskStateSegment {
+        TaskStateSegment {
+            reserved_1:             0,
+            privilege_stack_table:  [0; 3],
+            reserved_2:             0,
+            interrupt_stack_table:  [0; 7],
+            reserved_3:             0,
+            reserved_4:             0,
+            iomap_base:             mem::size_of::<TaskStateSegment>() as u16,  // no I/O permission bitmap
+        }
+    }
+}
+
+//============================================================
+/// Setup the known-good stacks of the (not yet loaded) TSS
+//
+//============================================================
+pub fn init() {
+    unsafe {
+        TSS.privilege_stack_table[0] = stack_top(&KERNEL_STACK);
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize]  = stack_top(&DOUBLE_FAULT_STACK);
+        TSS.interrupt_stack_table[NMI_IST_INDEX as usize]           = stack_top(&NMI_STACK);
+        TSS.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = stack_top(&MACHINE_CHECK_STACK);
+    }
+}
+
+//============================================================
+/// Set the stack loaded on ring 3 -> ring 0 transitions (RSP0)
+//
+//============================================================
+pub unsafe fn set_kernel_stack(stack_top: u64) {
+    TSS.privilege_stack_table[0] = stack_top;
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn kernel_stack() -> u64 {
+    unsafe { TSS.privilege_stack_table[0] }
+}
+
+//============================================================
+/// Address of the RSP0 slot (unaligned, the TSS is packed)
+//
+//============================================================
+pub fn kernel_stack_slot() -> *const u64 {
+    unsafe { ((&TSS as *const _ as u64) + 4) as *const u64 }
+}
+
+fn stack_top(stack: &'static Stack) -> u64 {
+    stack.0.as_ptr() as u64 + STACK_SIZE as u64     // stacks grow downwards
+}
@@ -0,0 +1,64 @@
+// KERNEL DEBUG COMMANDS
+//
+// Dumps of kernel state by name, for the console and for tests at boot.
+
+use crate::heap::{self, HeapAllocator};
+use crate::memory::FrameAllocator;
+use crate::process;
+use crate::time;
+
+const COMMANDS: [(&str, fn(), &str); 6] = [
+    ("help",   help,       "list the commands"),
+    ("heap",   heap_stats, "heap usage and fragmentation"),
+    ("nodes",  heap_nodes, "every heap node, boundary tags checked"),
+    ("slabs",  slabs,      "slab caches"),
+    ("vmas",   vmas,       "areas of the current process"),
+    ("uptime", uptime,     "time since the timer started"),
+];
+
+//============================================================
+/// Run a command, false if unknown
+//
+//============================================================
+pub fn command(name: &str) -> bool {
+    match COMMANDS.iter().find(|(command, _, _)| *command == name.trim()) {
+        Some((_, run, _)) => { run(); true }
+        None              => { crate::println!("unknown command {:?}, try \"help\"", name); false }
+    }
+}
+
+fn help() {
+    for (name, _, description) in COMMANDS.iter() {
+        crate::println!("  {:<8} {}", name, description);
+    }
+}
+
+fn heap_stats() {
+    match HeapAllocator::stats() {
+        Ok(stats)  => crate::println!("{}", stats),
+        Err(error) => crate::println!("heap corrupted: {:?}", error),
+    }
+    crate::println!("{} frames used, {} free", FrameAllocator::used_frames(), FrameAllocator::free_frames());
+}
+
+fn heap_nodes() {
+    let result = HeapAllocator::walk(&mut |node| {
+        crate::println!("  {:#014x} {:#8x} {}", node.address, node.size, if node.free { "free" } else { "busy" });
+    });
+    if let Err(error) = result {
+        crate::println!("heap corrupted: {:?}", error);
+    }
+}
+
+fn slabs() {
+    heap::slab::dump();
+}
+
+fn vmas() {
+    process::dump_areas();
+}
+
+fn uptime() {
+    let uptime = time::uptime();
+    crate::println!("up {}.{:03} s", uptime.as_secs(), uptime.subsec_millis());
+}
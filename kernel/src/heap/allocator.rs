@@ -1,19 +1,52 @@
 use alloc::alloc::{ GlobalAlloc, Layout };
 use core::ptr;
 use core::cmp;
-use super::node::{ Node, NodeHeader, NodeHeaderExt };
-use super::arena::{ Arena };
+use myos_heap::{ Arena, Node };
+use super::slab;
+#[cfg(feature = "heap-debug")]
+use super::debug;
+use super::stats::{ self, HeapStats };
+use super::{ HeapCorruption, NodeInfo };
+use crate::memory::FrameAllocator;
+use crate::paging::{ self, tlb, Mapper, PageTableFlags, VirtualAddress, PAGE_SIZE_4K };
+use crate::sync::IrqSpinlock;
 
 #[global_allocator]
-pub static mut ALLOCATOR: HeapAllocator = HeapAllocator::new();
+pub static ALLOCATOR: HeapAllocator = HeapAllocator::new();
 
-#[repr(align(16))]
-struct TestSpace([u8; 0x100000]);
+/// Virtual range of the heap (PML4 slot 252, shared by every context)
+pub const HEAP_START : u64 = 0x0000_7E00_0000_0000;
+pub const HEAP_END   : u64 = 0x0000_7E80_0000_0000;
 
-static mut TESTSPACE : TestSpace = TestSpace([0; 0x100000]);  // 1MB
+const INITIAL_SIZE      : u64 = 0x10_0000;     // 1MB, never given back
+const GROW_SIZE         : u64 = 0x1_0000;      // mapped at least this much at a time
+const RELEASE_THRESHOLD : u64 = 0x40_0000;     // free tail worth unmapping
+
+// The heap is made of segments of mapped memory, chained by their first word
+// (start of the next segment, 0 for the last one). Nodes are framed by zero
+// tags so that they never coalesce across segments:
+//
+// | next segment | - | 0 | node | node | ... | node | 0 |
+//
+// Growing extends the last segment when it ends at `next`, or starts a new
+// one there (a release is in flight above `top`).
+//
+// Free tails are not unmapped by the dealloc that cut them: the shootdown waits
+// for the other CPUs, which may be spinning on a lock the caller holds. The
+// timer tick releases them, with no lock held.
+pub(super) struct Heap {
+    pub(super) arena : Arena,
+    pub(super) last  : u64,    // start of the last segment, 0 before the first one
+    pub(super) top   : u64,    // end of the last segment
+    next             : u64,    // first address neither mapped nor being released
+    releasing        : Option<(u64, u64)>,     // cut off by `trim`, still mapped
+}
+
+/// Segment link and the arena's leading tag in front of the first node
+const SEGMENT_HEADER : u64 = 24;
 
 pub struct HeapAllocator {
-    arena : Arena,
+    heap : IrqSpinlock<Heap>,       // interrupt handlers allocate too
 }
 
 impl HeapAllocator {
@@ -23,90 +56,319 @@ impl HeapAllocator {
     //
     //============================================================
     pub const fn new() -> Self {
-        HeapAllocator { arena: Arena::new() }
+        HeapAllocator { heap: IrqSpinlock::new(Heap { arena: Arena::new(), last: 0, top: HEAP_START, next: HEAP_START, releasing: None }) }
     }
 
     //============================================================
-    //
+    // map the first segment (needs the frame allocator and paging)
     //
     //============================================================
     pub fn init() {
+        assert!(paging::translate_addr(VirtualAddress(HEAP_START)).is_none(), "heap range already in use");
+        assert!(ALLOCATOR.heap.lock().grow(INITIAL_SIZE), "no memory for the kernel heap");
+    }
+}
+
+impl Heap {
+
+    //============================================================
+    // map room for a node of `size` bytes and free it into the arena
+    //
+    //============================================================
+    fn grow(&mut self, size: u64) -> bool {
+
+        let length = (size + SEGMENT_HEADER + 8 + GROW_SIZE - 1) & !(GROW_SIZE - 1);
+        let start  = self.next;
+
+        if length > HEAP_END - start || !map(start, start + length) {
+            return false;
+        }
+
+        // fresh frames are zeroed: the link of a new segment is already there
+        let contiguous = self.top == start && self.last != 0;
         unsafe {
-            let ptr = TESTSPACE.0.as_mut_ptr();
+            match contiguous {
+                true  => self.arena.extend(start, start + length),
+                false => {
+                    if self.last != 0 {
+                        ptr::write(self.last as *mut u64, start);
+                    }
+                    self.last = start;
+                    self.arena.add_region(start + 8, start + length)
+                }
+            }
+        }
+
+        self.top  = start + length;
+        self.next = start + length;
+        true
+    }
+
+    //============================================================
+    // cut a large free tail off the last segment, left to
+    // `release_deferred` to unmap
+    //============================================================
+    fn trim(&mut self) {
+
+        if self.top != self.next {
+            return;     // already releasing
+        }
 
-            let len = TESTSPACE.0.len() as u64;
+        let tag = unsafe { ptr::read((self.top - 16) as *const u64) };
+        if tag & 0x1 != 0 || tag == 0 {
+            return;     // last node in use
+        }
 
-            let SPACE = Node::new(ptr.add(0x8), len-16);
+        let start = self.top - 8 - tag;
+        let keep  = cmp::max((start + GROW_SIZE + PAGE_SIZE_4K - 1) & !(PAGE_SIZE_4K - 1), HEAP_START + INITIAL_SIZE);
 
-            ALLOCATOR.arena.push_node(&SPACE);
+        if keep + RELEASE_THRESHOLD > self.top {
+            return;
         }
+
+        unsafe {
+            let mut tail = Node::from(start as *mut u8);
+            self.arena.remove_node(&mut tail);
+
+            let tail = Node::new(start as *mut u8, keep - 8 - start);
+            ptr::write((keep - 8) as *mut u64, 0);     // new end tag
+            self.arena.push_node(&tail);
+        }
+
+        self.releasing = Some((keep, self.top));
+        self.top = keep;
     }
 
     //============================================================
-    //
-    //
+    // the range above `top` is unmapped: reuse it unless a new
+    // segment was started behind it meanwhile
     //============================================================
-    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+    fn released(&mut self, start: u64, end: u64) {
+        if self.top == start && self.next == end {
+            self.next = start;
+        }
+    }
+}
 
-        let size = cmp::max(layout.size() + 16, 32) as u64;
-        let node = self.arena.find_node(size);
+// Back [start, end) with new frames, all or nothing
+fn map(start: u64, end: u64) -> bool {
 
-        match node {
-            None => {
-                return ptr::null_mut()  // NO SPACE LEFT
+    let mut mapper = Mapper::new();
+
+    for page in (start..end).step_by(PAGE_SIZE_4K as usize) {
+        let mapped = match FrameAllocator::allocate_frame() {
+            Some(frame) => match mapper.map_to(page, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE) {
+                Ok(())  => true,
+                Err(_)  => { FrameAllocator::deallocate_frame(frame); false }    // no frame for a page table
             },
-            Some(mut node) => {
+            None => false,
+        };
+
+        if !mapped {
+            unhook(start, page);
+            tlb::flush_all();   // never handed out, only this CPU walked them
+            free_frames(start, page);
+            return false;
+        }
+    }
+    true
+}
+
+// Make [start, end) non-present, each entry keeps its frame for `free_frames`
+fn unhook(start: u64, end: u64) {
+
+    let mut mapper = Mapper::new();
+
+    for page in (start..end).step_by(PAGE_SIZE_4K as usize) {
+        if let Some(entry) = mapper.entry_mut(page).filter(|entry| entry.is_present()) {
+            entry.set_flags(entry.flags() - PageTableFlags::PRESENT);
+        }
+    }
+}
+
+// Give the frames of an unhooked range back, once no TLB holds them anymore
+fn free_frames(start: u64, end: u64) {
+
+    let mut mapper = Mapper::new();
+
+    for page in (start..end).step_by(PAGE_SIZE_4K as usize) {
+        if let Some(entry) = mapper.entry_mut(page).filter(|entry| !entry.is_unused()) {
+            let frame = entry.address().0;
+            entry.set_unused();
+            FrameAllocator::deallocate_frame(frame);
+        }
+    }
+}
+
+// Slab cache serving a small layout, and the offset of the block in its objects
+#[cfg(not(feature = "heap-debug"))]
+fn slab_for(layout: Layout) -> Option<(&'static slab::Cache, usize)> {
+    slab::cache_for(layout).map(|cache| (cache, 0))
+}
+
+// Same, the objects also hold a Header and a red zone (heap-debug)
+#[cfg(feature = "heap-debug")]
+fn slab_for(layout: Layout) -> Option<(&'static slab::Cache, usize)> {
+    let (object, front) = debug::object_layout(layout);
+    slab::cache_for(object).map(|cache| (cache, front))
+}
 
-                if node.size() - size > 32 {
-                    let (slice, leftover) = node.split(size);
-                    self.arena.push_node(&leftover);
-                    node = slice;
+unsafe impl GlobalAlloc for HeapAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+
+        let ptr = self.allocate_block(layout);
+        if !ptr.is_null() {
+            stats::allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+
+        stats::deallocated(layout.size());
+        self.deallocate_block(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+
+        match (slab_for(layout), slab_for(new_layout)) {
+            (Some((old, _)), Some((new, _))) if old as *const _ == new as *const _ => {
+                #[cfg(feature = "heap-debug")]
+                {
+                    let end = ptr.sub(debug::object_layout(layout).1).add(old.object_size());
+                    debug::check_object(ptr, layout.size(), end, debug::site());
+                    debug::arm_object(ptr, new_size, end, debug::site());
+                }
+
+                stats::resized(layout.size(), new_size);
+                return ptr;
+            }
+            (None, None) => {
+                #[cfg(feature = "heap-debug")]
+                debug::check(ptr, layout.size());
+
+                let resized = {
+                    let mut heap = self.heap.lock();
+                    let resized = heap.arena.reallocate(ptr, new_layout);
+                    heap.trim();
+                    resized
+                };
+
+                #[cfg(feature = "heap-debug")]
+                if resized {
+                    debug::arm(ptr, new_size, debug::site());
                 }
 
-                node.set_free(false);
-                node.buffer.cast::<u8>()
+                if resized {
+                    stats::resized(layout.size(), new_size);
+                    return ptr;
+                }
             }
+            _ => {}
         }
+
+        // move it
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, cmp::min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
     }
+}
+
+impl HeapAllocator {
 
     //============================================================
-    //
+    // slab for small layouts, arena for the others
     //
     //============================================================
-    unsafe fn deallocate(&mut self, allocation_ptr: *mut u8) {
-
-        let mut allocation = Node::from(allocation_ptr);
-        allocation.set_free(true);
+    unsafe fn allocate_block(&self, layout: Layout) -> *mut u8 {
 
-        if let Some(mut previous) = allocation.previous() {
-            if previous.is_free() {
-                self.arena.remove_node(&mut previous);
-                allocation = previous.coalesce(allocation);
+        if let Some((cache, front)) = slab_for(layout) {
+            let object = cache.allocate();
+            if object.is_null() {
+                return object;
             }
+
+            #[cfg(feature = "heap-debug")]
+            debug::arm_object(object.add(front), layout.size(), object.add(cache.object_size()), debug::site());
+
+            return object.add(front);
         }
 
-        if let Some(mut next) = allocation.next() {
-            if next.is_free() {
-                self.arena.remove_node(&mut next);
-                allocation = allocation.coalesce(next);
+        let block = {
+            let mut heap = self.heap.lock();
+
+            let mut block = heap.arena.allocate(layout);
+            if block.is_null() && heap.grow(Arena::search_size(layout)) {
+                block = heap.arena.allocate(layout);
             }
+            block
+        };
+
+        #[cfg(feature = "heap-debug")]
+        if !block.is_null() {
+            debug::arm(block, layout.size(), debug::site());
+        }
+
+        block
+    }
+
+    //============================================================
+    //
+    //
+    //============================================================
+    unsafe fn deallocate_block(&self, ptr: *mut u8, layout: Layout) {
+
+        if let Some((cache, front)) = slab_for(layout) {
+            let object = ptr.sub(front);
+
+            #[cfg(feature = "heap-debug")]
+            debug::check_object(ptr, layout.size(), object.add(cache.object_size()), debug::site());
+
+            return cache.free(object);
         }
 
-        // DEBUG - MEMSET 0
-        allocation = Node::new(allocation.buffer.cast::<u8>(), allocation.size());
+        #[cfg(feature = "heap-debug")]
+        debug::check(ptr, layout.size());
 
-        self.arena.push_node(&allocation);
+        let mut heap = self.heap.lock();
+        heap.arena.deallocate(ptr);
+        heap.trim();
     }
-}
 
-unsafe impl GlobalAlloc for HeapAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        crate::println!("[alloc()] size: 0x{:x}", layout.size());
-        ALLOCATOR.allocate(layout).add(8)
+    //============================================================
+    // unmap the tail cut off by `trim`, from the timer tick: the
+    // caller must hold no lock (the shootdown waits for the others)
+    //============================================================
+    pub fn release_deferred() {
+
+        let released = ALLOCATOR.heap.lock().releasing.take();
+
+        if let Some((start, end)) = released {
+            unhook(start, end);
+            tlb::shootdown_all();
+            free_frames(start, end);
+            ALLOCATOR.heap.lock().released(start, end);
+        }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        crate::println!("[dealloc()] size: 0x{:x}", layout.size());
-        ALLOCATOR.deallocate(ptr.sub(8));
+    //============================================================
+    // snapshot of the arena, slabs and counters
+    //
+    //============================================================
+    pub fn stats() -> Result<HeapStats, HeapCorruption> {
+        stats::collect(&ALLOCATOR.heap.lock())
+    }
+
+    //============================================================
+    // every node in address order, boundary tags checked on the way
+    // (`visit` runs under the heap lock: it must not allocate)
+    //============================================================
+    pub fn walk(visit: &mut dyn FnMut(&NodeInfo)) -> Result<(), HeapCorruption> {
+        stats::walk(&ALLOCATOR.heap.lock(), visit).map(|_| ())
     }
 }
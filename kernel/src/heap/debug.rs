@@ -0,0 +1,50 @@
+use alloc::alloc::Layout;
+use core::ptr;
+use crate::paging::{ self, VirtualAddress };
+
+// HEAP DEBUG MODE (feature "heap-debug"): the checks live in the heap crate,
+// allocation sites are return addresses from the frame pointer chain.
+//
+// Small layouts still go to the slabs, as in the shipped kernel, in objects
+// grown to hold the same Header and red zone as arena blocks:
+//
+// | padding | Header | block ... | red zone |
+//
+// Free objects are poisoned, double frees of objects from named caches (no
+// Header) are caught by a busy bitmap per slab.
+
+pub use myos_heap::debug::{ arm, arm_object, check, check_object, check_poisoned_object, poison, HEADER, RED_ZONE, SITE_DEPTH };
+
+//============================================================
+// slab object around a block of `layout`, and the offset of
+// the block in it (aligned, room for the Header)
+//============================================================
+pub fn object_layout(layout: Layout) -> (Layout, usize) {
+
+    let front  = (HEADER as usize + layout.align() - 1) & !(layout.align() - 1);
+    let object = unsafe { Layout::from_size_align_unchecked(front + layout.size() + RED_ZONE as usize, layout.align()) };
+    (object, front)
+}
+
+//============================================================
+// return addresses of the allocating code, innermost first
+//
+//============================================================
+#[inline(always)]
+pub fn site() -> [u64; SITE_DEPTH] {
+
+    let mut site = [0; SITE_DEPTH];
+    let mut frame: u64;
+    unsafe { llvm_asm!("movq %rbp, $0" : "=r"(frame) ::: "volatile"); }
+
+    for address in site.iter_mut() {
+        if frame == 0 || frame % 8 != 0 || paging::translate_addr(VirtualAddress(frame + 8)).is_none() {
+            break;
+        }
+        unsafe {
+            *address = ptr::read((frame + 8) as *const u64);
+            frame    = ptr::read(frame as *const u64);
+        }
+    }
+    site
+}
@@ -1,5 +1,10 @@
-mod arena;
-mod node;
 mod allocator;
+pub mod slab;
+pub mod selftest;
+mod stats;
+#[cfg(feature = "heap-debug")]
+mod debug;
 
 pub use allocator::HeapAllocator;
+pub use myos_heap::{ HeapCorruption, NodeInfo };
+pub use stats::HeapStats;
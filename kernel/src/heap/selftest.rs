@@ -0,0 +1,55 @@
+use alloc::alloc::{ alloc, dealloc, Layout };
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::{ ptr, slice };
+
+// Regression checks run at boot, the kernel has no test harness
+
+const ALIGNMENTS : [usize; 10] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096];
+const SIZES      : [usize; 6]  = [1, 24, 100, 2049, 3000, 5000];
+
+#[repr(align(4096))]
+struct Page([u8; 4096]);
+
+//============================================================
+// every alignment up to a page, slab and arena sized, freed
+// in both orders
+//============================================================
+pub fn run() {
+
+    for &reverse in [false, true].iter() {
+        let mut blocks = Vec::new();
+
+        for &align in ALIGNMENTS.iter() {
+            for &size in SIZES.iter() {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let block  = unsafe { alloc(layout) };
+
+                assert!(!block.is_null(), "heap self-test: out of memory for {:?}", layout);
+                assert!(block as usize % align == 0, "heap self-test: {:p} misaligned for {:?}", block, layout);
+
+                unsafe { ptr::write_bytes(block, align as u8, size); }
+                blocks.push((block, layout));
+            }
+        }
+
+        // neighbours were not overwritten
+        for &(block, layout) in blocks.iter() {
+            let bytes = unsafe { slice::from_raw_parts(block, layout.size()) };
+            assert!(bytes.iter().all(|&byte| byte == layout.align() as u8), "heap self-test: {:p} overwritten", block);
+        }
+
+        if reverse {
+            blocks.reverse();
+        }
+        for (block, layout) in blocks {
+            unsafe { dealloc(block, layout); }
+        }
+    }
+
+    // like a boxed PageTable
+    let page = Box::new(Page([0; 4096]));
+    assert!(&*page as *const Page as usize % 4096 == 0, "heap self-test: boxed page misaligned");
+
+    crate::println!("Heap self-test passed");
+}
@@ -0,0 +1,329 @@
+use alloc::alloc::Layout;
+use core::{ cmp, mem, ptr };
+use crate::memory::FrameAllocator;
+use crate::paging::{ PAGE_SIZE_4K, PHYSICAL_MEMORY_OFFSET };
+use crate::sync::IrqSpinlock;
+#[cfg(feature = "heap-debug")]
+use super::debug;
+
+// A slab is a run of frames, aligned to its own size and reached through the
+// physical memory window, cut into objects of one size:
+//
+// | Slab header | object | object | ... | object |
+//
+// The owning slab of an object is found by masking its address. Free objects
+// are chained through their first 8 bytes.
+
+pub const SLAB_MINIMUM_OBJECT : usize = 16;
+pub const SLAB_MAXIMUM_OBJECT : usize = 2048;
+
+const SLAB_OBJECTS_MINIMUM : usize = 8;     // slabs grow until this many objects fit
+
+pub type Constructor = fn(*mut u8);
+
+struct Slab {
+    next     : *mut Slab,       // slabs with free objects (null if last)
+    previous : *mut Slab,
+    free     : *mut FreeObject,
+    in_use   : u32,
+    #[cfg(feature = "heap-debug")]
+    busy     : [u64; 8],        // one bit per object handed out (a 4K slab holds at most 512)
+    #[cfg(feature = "heap-debug")]
+    cache    : *const Cache,    // owner, a free through another cache is caught
+}
+
+struct FreeObject {
+    next : *mut FreeObject,
+}
+
+struct Slabs {
+    available : *mut Slab,      // slabs with at least one free object, full ones are unlinked
+    count     : usize,          // all slabs, full ones included
+}
+
+// Only reached through the cache lock
+unsafe impl Send for Slabs {}
+
+pub struct Cache {
+    name        : &'static str,
+    size        : usize,        // object stride
+    align       : usize,
+    slab_size   : usize,
+    constructor : Option<Constructor>,
+    slabs       : IrqSpinlock<Slabs>,
+}
+
+// General purpose caches, one per power of two
+static CACHES: [Cache; 8] = [
+    Cache::new("size-16",   16,   16,   None),
+    Cache::new("size-32",   32,   32,   None),
+    Cache::new("size-64",   64,   64,   None),
+    Cache::new("size-128",  128,  128,  None),
+    Cache::new("size-256",  256,  256,  None),
+    Cache::new("size-512",  512,  512,  None),
+    Cache::new("size-1024", 1024, 1024, None),
+    Cache::new("size-2048", 2048, 2048, None),
+];
+
+//============================================================
+// general cache serving a layout, None for large ones (arena)
+//
+//============================================================
+pub fn cache_for(layout: Layout) -> Option<&'static Cache> {
+
+    let size = cmp::max(cmp::max(layout.size(), layout.align()), SLAB_MINIMUM_OBJECT).next_power_of_two();
+    if size > SLAB_MAXIMUM_OBJECT {
+        return None;
+    }
+
+    let index = (size.trailing_zeros() - SLAB_MINIMUM_OBJECT.trailing_zeros()) as usize;
+    Some(&CACHES[index])
+}
+
+//============================================================
+// frames held by the general caches, in bytes
+//
+//============================================================
+pub fn bytes() -> u64 {
+    CACHES.iter().map(|cache| cache.bytes()).sum()
+}
+
+//============================================================
+// one line per general cache
+//
+//============================================================
+pub fn dump() {
+    for cache in CACHES.iter() {
+        cache.dump();
+    }
+}
+
+impl Cache {
+
+    //============================================================
+    // named cache of `size` bytes objects, `constructor` initializes
+    // every object handed out
+    //============================================================
+    pub const fn new(name: &'static str, size: usize, align: usize, constructor: Option<Constructor>) -> Cache {
+
+        let align = if align < 8 { 8 } else { align };            // room for the free chain
+        let size  = if size < 8 { 8 } else { size };
+        let size  = (size + align - 1) & !(align - 1);
+
+        let mut slab_size = PAGE_SIZE_4K as usize;
+        while Self::fit(slab_size, size, align) < SLAB_OBJECTS_MINIMUM {
+            slab_size *= 2;
+        }
+
+        Cache { name, size, align, slab_size, constructor, slabs: IrqSpinlock::new(Slabs { available: ptr::null_mut(), count: 0 }) }
+    }
+
+    //============================================================
+    // size of the objects, red zones included (checked on free)
+    //
+    //============================================================
+    #[cfg(feature = "heap-debug")]
+    pub fn object_size(&self) -> usize {
+        self.size
+    }
+
+    //============================================================
+    // frames held, in bytes
+    //
+    //============================================================
+    pub fn bytes(&self) -> u64 {
+        (self.slabs.lock().count * self.slab_size) as u64
+    }
+
+    //============================================================
+    // null when out of frames
+    //
+    //============================================================
+    pub fn allocate(&self) -> *mut u8 {
+
+        let object = {
+            let mut slabs = self.slabs.lock();
+
+            if slabs.available.is_null() {
+                match self.new_slab() {
+                    Some(slab) => { slabs.link(slab); slabs.count += 1; }
+                    None       => return ptr::null_mut(),
+                }
+            }
+
+            unsafe {
+                let slab = &mut *slabs.available;
+                let object = slab.free;
+
+                slab.free    = (*object).next;
+                slab.in_use += 1;
+
+                if slab.free.is_null() {
+                    slabs.unlink(slab);     // full
+                }
+
+                #[cfg(feature = "heap-debug")]
+                {
+                    debug::check_poisoned_object(object.cast::<u8>(), self.size, self.name);
+                    let (word, bit) = self.busy_bit(slab, object.cast::<u8>());
+                    slab.busy[word] |= bit;
+                }
+
+                object.cast::<u8>()
+            }
+        };
+
+        if let Some(constructor) = self.constructor {
+            constructor(object);
+        }
+        object
+    }
+
+    //============================================================
+    // give back an object from this cache's `allocate`
+    //
+    //============================================================
+    pub unsafe fn free(&self, object: *mut u8) {
+
+        let slab = ((object as usize) & !(self.slab_size - 1)) as *mut Slab;
+
+        #[cfg(feature = "heap-debug")]
+        if (*slab).cache != self as *const Cache || (object as usize - slab as usize - self.first_object()) % self.size != 0 {
+            panic!("heap: {:p} is not an object of cache {} (freed with another Layout?)", object, self.name);
+        }
+
+        debug_assert!((object as usize - slab as usize - self.first_object()) % self.size == 0, "{}: {:p} is not an object", self.name, object);
+
+        let mut slabs = self.slabs.lock();
+        let slab = &mut *slab;
+
+        if slab.free.is_null() {
+            slabs.link(slab);       // was full
+        }
+
+        #[cfg(feature = "heap-debug")]
+        {
+            let (word, bit) = self.busy_bit(slab, object);
+            if slab.busy[word] & bit == 0 {
+                panic!("heap: double free of {:p} (object of cache {}, size {:#x}, freed again from {:x?})", object, self.name, self.size, debug::site());
+            }
+            slab.busy[word] &= !bit;
+            debug::poison(object as u64 + 8, (object as usize + self.size) as u64);
+        }
+
+        let object = object.cast::<FreeObject>();
+        (*object).next = slab.free;
+        slab.free      = object;
+        slab.in_use   -= 1;
+
+        // keep one empty slab around, give the others back
+        if slab.in_use == 0 && slabs.count > 1 {
+            slabs.unlink(slab);
+            slabs.count -= 1;
+
+            let frame = slab as *mut Slab as u64 - PHYSICAL_MEMORY_OFFSET;
+            FrameAllocator::deallocate_frames(frame, (self.slab_size as u64) / PAGE_SIZE_4K);
+        }
+    }
+
+    //============================================================
+    //
+    //
+    //============================================================
+    pub fn dump(&self) {
+
+        let slabs = self.slabs.lock();
+        let capacity = Self::fit(self.slab_size, self.size, self.align);
+
+        crate::println!("{:<12} object {:>5} slab {:>6} slabs {:>4} ({} objects each)",
+            self.name, self.size, self.slab_size, slabs.count, capacity);
+    }
+
+    // objects per slab
+    const fn fit(slab_size: usize, size: usize, align: usize) -> usize {
+        let first = (mem::size_of::<Slab>() + align - 1) & !(align - 1);
+        if first >= slab_size { 0 } else { (slab_size - first) / size }
+    }
+
+    fn first_object(&self) -> usize {
+        (mem::size_of::<Slab>() + self.align - 1) & !(self.align - 1)
+    }
+
+    // word and mask of an object in its slab's busy bitmap
+    #[cfg(feature = "heap-debug")]
+    fn busy_bit(&self, slab: &Slab, object: *mut u8) -> (usize, u64) {
+        let index = (object as usize - slab as *const Slab as usize - self.first_object()) / self.size;
+        (index / 64, 1 << (index % 64))
+    }
+
+    // frames for a new slab, every object free
+    fn new_slab(&self) -> Option<*mut Slab> {
+
+        let frames = (self.slab_size as u64) / PAGE_SIZE_4K;
+        let frame  = FrameAllocator::allocate_frames(frames, self.slab_size as u64)?;
+        let base   = (PHYSICAL_MEMORY_OFFSET + frame) as *mut u8;
+
+        let capacity = Self::fit(self.slab_size, self.size, self.align);
+
+        #[cfg(feature = "heap-debug")]
+        assert!(capacity <= 512, "{}: {} objects per slab, too many for the busy bitmap", self.name, capacity);
+
+        unsafe {
+            // chain the objects in address order
+            let mut free = ptr::null_mut::<FreeObject>();
+            for index in (0..capacity).rev() {
+                let object = base.add(self.first_object() + index * self.size).cast::<FreeObject>();
+                (*object).next = free;
+
+                #[cfg(feature = "heap-debug")]
+                debug::poison(object as u64 + 8, (object as usize + self.size) as u64);
+
+                free = object;
+            }
+
+            let slab = base.cast::<Slab>();
+            ptr::write(slab, Slab {
+                next: ptr::null_mut(), previous: ptr::null_mut(), free, in_use: 0,
+                #[cfg(feature = "heap-debug")]
+                busy: [0; 8],
+                #[cfg(feature = "heap-debug")]
+                cache: self,
+            });
+            Some(slab)
+        }
+    }
+}
+
+impl Slabs {
+
+    // push on the available list
+    fn link(&mut self, slab: *mut Slab) {
+        unsafe {
+            (*slab).previous = ptr::null_mut();
+            (*slab).next     = self.available;
+
+            if !self.available.is_null() {
+                (*self.available).previous = slab;
+            }
+            self.available = slab;
+        }
+    }
+
+    // take off the available list
+    fn unlink(&mut self, slab: *mut Slab) {
+        unsafe {
+            let (previous, next) = ((*slab).previous, (*slab).next);
+
+            match previous.is_null() {
+                true  => self.available = next,
+                false => (*previous).next = next,
+            }
+            if !next.is_null() {
+                (*next).previous = previous;
+            }
+
+            (*slab).previous = ptr::null_mut();
+            (*slab).next     = ptr::null_mut();
+        }
+    }
+}
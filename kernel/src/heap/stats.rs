@@ -0,0 +1,140 @@
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{ AtomicU64, Ordering };
+use myos_heap::Arena;
+use super::allocator::{ Heap, HEAP_START };
+use super::slab;
+use super::{ HeapCorruption, NodeInfo };
+
+// Caller side counters (layout sizes, slabs included)
+static ALLOCATIONS   : AtomicU64 = AtomicU64::new(0);
+static DEALLOCATIONS : AtomicU64 = AtomicU64::new(0);
+static REQUESTED     : AtomicU64 = AtomicU64::new(0);
+static PEAK          : AtomicU64 = AtomicU64::new(0);
+
+//============================================================
+// Copied as is to user space by the heap_stats syscall
+//
+//============================================================
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct HeapStats {
+    pub total         : u64,        // mapped for the arena
+    pub used          : u64,        // busy nodes, boundary tags included
+    pub free          : u64,        // free nodes
+    pub largest_free  : u64,
+    pub slab          : u64,        // frames held by the slab caches
+    pub requested     : u64,        // bytes allocated by callers right now
+    pub peak          : u64,        // highest `requested` so far
+    pub allocations   : u64,
+    pub deallocations : u64,
+    pub free_lists    : [u64; 64],  // free nodes per arena size class
+}
+
+//============================================================
+//
+//
+//============================================================
+pub(super) fn allocated(size: usize) {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    grown(size as u64);
+}
+
+pub(super) fn deallocated(size: usize) {
+    DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    REQUESTED.fetch_sub(size as u64, Ordering::Relaxed);
+}
+
+// in place, not a new block
+pub(super) fn resized(old: usize, new: usize) {
+    match new > old {
+        true  => grown((new - old) as u64),
+        false => { REQUESTED.fetch_sub((old - new) as u64, Ordering::Relaxed); }
+    }
+}
+
+fn grown(size: u64) {
+    let requested = REQUESTED.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(requested, Ordering::Relaxed);
+}
+
+//============================================================
+// walk the segments, then add the counters
+//
+//============================================================
+pub(super) fn collect(heap: &Heap) -> Result<HeapStats, HeapCorruption> {
+
+    let mut stats = HeapStats {
+        total: 0, used: 0, free: 0, largest_free: 0,
+        slab:          slab::bytes(),
+        requested:     REQUESTED.load(Ordering::Relaxed),
+        peak:          PEAK.load(Ordering::Relaxed),
+        allocations:   ALLOCATIONS.load(Ordering::Relaxed),
+        deallocations: DEALLOCATIONS.load(Ordering::Relaxed),
+        free_lists:    [0; 64],
+    };
+
+    let (mut used, mut free, mut largest_free) = (0, 0, 0);
+
+    stats.total = walk(heap, &mut |node| {
+        match node.free {
+            true  => { free += node.size; largest_free = largest_free.max(node.size); }
+            false => used += node.size,
+        }
+    })?;
+
+    stats.used         = used;
+    stats.free         = free;
+    stats.largest_free = largest_free;
+
+    for (class, length) in stats.free_lists.iter_mut().enumerate() {
+        *length = heap.arena.list_length(class);
+    }
+    Ok(stats)
+}
+
+//============================================================
+// visit every node in address order, returns the bytes spanned
+// by the segments
+//============================================================
+pub(super) fn walk(heap: &Heap, visit: &mut dyn FnMut(&NodeInfo)) -> Result<u64, HeapCorruption> {
+
+    let mut total  = 0;
+    let mut walked = 0;
+    let mut segment = if heap.last == 0 { 0 } else { HEAP_START };
+
+    while segment != 0 {
+        let link  = unsafe { ptr::read(segment as *const u64) };
+        let limit = match link { 0 => heap.top, _ => link };
+
+        let end = unsafe {
+            Arena::walk(segment + 8, limit, &mut |node| {
+                walked += node.free as u64;
+                visit(node);
+            })?
+        };
+
+        total  += end - segment;
+        segment = link;
+    }
+
+    let listed = heap.arena.free_nodes();
+    if walked != listed {
+        return Err(HeapCorruption::FreeLists { walked, listed });
+    }
+    Ok(total)
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "arena {:#x} bytes: {:#x} used, {:#x} free (largest {:#x})", self.total, self.used, self.free, self.largest_free)?;
+        writeln!(f, "slabs {:#x} bytes", self.slab)?;
+        writeln!(f, "requested {:#x} bytes (peak {:#x}), {} allocations, {} deallocations",
+            self.requested, self.peak, self.allocations, self.deallocations)?;
+        write!(f, "free lists:")?;
+        for (class, &length) in self.free_lists.iter().enumerate().filter(|(_, &length)| length != 0) {
+            write!(f, " [{}] {}", class, length)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+// LOCAL APIC (xAPIC through MMIO, x2APIC through MSRs)
+
+use core::ptr;
+use crate::acpi::madt::{Madt, Polarity, TriggerMode};
+use crate::cpu::{self, msr};
+use crate::paging::{self, PhysicalAddress};
+use super::irq::{self, InterruptFrame, IrqReturn};
+
+pub const ERROR_VECTOR:    u8 = 0xFE;
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const IA32_APIC_BASE:      u32 = 0x1B;
+const APIC_BASE_X2APIC:    u64 = 1 << 10;
+const APIC_BASE_ENABLE:    u64 = 1 << 11;
+
+const CPUID_APIC:   u32 = 1 << 9;      // leaf 1, edx
+const CPUID_X2APIC: u32 = 1 << 21;     // leaf 1, ecx
+
+// Register offsets (xAPIC MMIO), x2APIC MSR = 0x800 + offset / 16
+pub const REG_ID:              u32 = 0x020;
+pub const REG_VERSION:         u32 = 0x030;
+pub const REG_TPR:             u32 = 0x080;
+pub const REG_EOI:             u32 = 0x0B0;
+pub const REG_SVR:             u32 = 0x0F0;
+pub const REG_ESR:             u32 = 0x280;
+pub const REG_ICR_LOW:         u32 = 0x300;
+pub const REG_ICR_HIGH:        u32 = 0x310;
+pub const REG_LVT_TIMER:       u32 = 0x320;
+pub const REG_LVT_LINT0:       u32 = 0x350;
+pub const REG_LVT_LINT1:       u32 = 0x360;
+pub const REG_LVT_ERROR:       u32 = 0x370;
+pub const REG_TIMER_INITIAL:   u32 = 0x380;
+pub const REG_TIMER_CURRENT:   u32 = 0x390;
+pub const REG_TIMER_DIVIDE:    u32 = 0x3E0;
+
+const SVR_ENABLE:          u32 = 1 << 8;
+const LVT_MASKED:          u32 = 1 << 16;
+const LVT_LEVEL_TRIGGERED: u32 = 1 << 15;
+const LVT_ACTIVE_LOW:      u32 = 1 << 13;
+const LVT_DELIVERY_NMI:    u32 = 0b100 << 8;
+const ICR_DELIVERY_STATUS: u32 = 1 << 12;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Disabled,
+    XApic(u64),     // virtual address of the register page
+    X2Apic,
+}
+
+static mut MODE: Mode = Mode::Disabled;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeliveryMode {
+    Fixed = 0b000,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpiDestination {
+    AllExcludingSelf,
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn is_supported() -> bool {
+    let (_, _, _, edx) = cpu::cpuid(1, 0);
+    edx & CPUID_APIC != 0
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn is_enabled() -> bool {
+    unsafe { MODE != Mode::Disabled }
+}
+
+//============================================================
+// PER CPU - prefers x2APIC when available
+//
+//============================================================
+pub fn init(madt: &Madt) {
+    unsafe {
+        let (_, _, ecx, _) = cpu::cpuid(1, 0);
+        let base = msr::read(IA32_APIC_BASE);
+
+        if ecx & CPUID_X2APIC != 0 {
+            msr::write(IA32_APIC_BASE, base | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+            MODE = Mode::X2Apic;
+        } else {
+            let registers = paging::mmio::map(PhysicalAddress(madt.local_apic_address), 0x1000);
+            msr::write(IA32_APIC_BASE, base | APIC_BASE_ENABLE);
+            MODE = Mode::XApic(registers.0);
+        }
+    }
+
+    write(REG_TPR, 0);                              // accept every priority class
+    write(REG_LVT_TIMER, LVT_MASKED);
+    write(REG_LVT_LINT0, LVT_MASKED);
+    write(REG_LVT_LINT1, LVT_MASKED);
+    irq::register_irq(ERROR_VECTOR, error_handler, 0).expect("APIC error vector refused");
+    write(REG_LVT_ERROR, ERROR_VECTOR as u32);
+
+    // NMI wiring from the MADT (0xff: every processor)
+    let processor = id();
+    for nmi in madt.nmis.iter() {
+        let applies = nmi.processor_id == 0xff || madt.processors.iter()
+            .any(|p| p.processor_id == nmi.processor_id as u32 && p.apic_id == processor);
+
+        if applies {
+            let mut lvt = LVT_DELIVERY_NMI;
+            if nmi.polarity == Polarity::ActiveLow { lvt |= LVT_ACTIVE_LOW; }
+            if nmi.trigger == TriggerMode::Level   { lvt |= LVT_LEVEL_TRIGGERED; }
+            write(if nmi.lint == 0 { REG_LVT_LINT0 } else { REG_LVT_LINT1 }, lvt);
+        }
+    }
+
+    write(REG_ESR, 0);                              // clear pending errors (back to back writes)
+    write(REG_ESR, 0);
+
+    write(REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+
+    eoi();                                          // ack anything left over
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn id() -> u32 {
+    match unsafe { MODE } {
+        Mode::X2Apic => read(REG_ID),
+        _            => read(REG_ID) >> 24,
+    }
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn eoi() {
+    write(REG_EOI, 0);
+}
+
+//============================================================
+/// Send an Inter-Processor Interrupt
+//
+//============================================================
+pub fn send_ipi(destination: IpiDestination, mode: DeliveryMode, vector: u8) {
+
+    let (target, shorthand) = match destination {
+        IpiDestination::AllExcludingSelf => (0, 0b11),
+    };
+
+    let low = (shorthand << 18) | ((mode as u32) << 8) | vector as u32;
+
+    unsafe {
+        match MODE {
+            Mode::X2Apic => msr::write(0x800 + (REG_ICR_LOW >> 4), (target as u64) << 32 | low as u64),
+            Mode::XApic(_) => {
+                write(REG_ICR_HIGH, target << 24);
+                write(REG_ICR_LOW, low);                // writing the low half sends the IPI
+                while read(REG_ICR_LOW) & ICR_DELIVERY_STATUS != 0 {}
+            }
+            Mode::Disabled => {}
+        }
+    }
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn read(register: u32) -> u32 {
+    unsafe {
+        match MODE {
+            Mode::XApic(base) => ptr::read_volatile((base + register as u64) as *const u32),
+            Mode::X2Apic      => msr::read(0x800 + (register >> 4)) as u32,
+            Mode::Disabled    => 0,
+        }
+    }
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn write(register: u32, value: u32) {
+    unsafe {
+        match MODE {
+            Mode::XApic(base) => ptr::write_volatile((base + register as u64) as *mut u32, value),
+            Mode::X2Apic      => msr::write(0x800 + (register >> 4), value as u64),
+            Mode::Disabled    => {}
+        }
+    }
+}
+
+fn error_handler(_frame: &mut InterruptFrame, _context: usize) -> IrqReturn {
+    write(REG_ESR, 0);                              // latch the error status
+    crate::println!("APIC ERROR: {:#x}", read(REG_ESR));
+    IrqReturn::Handled
+}
@@ -0,0 +1,215 @@
+use core::fmt;
+use crate::cpu;
+use crate::cpu::Registers;
+use crate::cpu::tss;
+use crate::memory::fault::{self, PageFault, Resolution};
+use crate::paging::{self, VirtualAddress};
+use crate::process;
+use super::idt::{InterruptDescriptorTable, InterruptStackFrameValue, PageFaultErrorCode, SelectorErrorCode};
+
+// Every exception enters through a small assembly stub that pushes a zero error
+// code when the CPU does not push one, then its vector, and jumps to a common
+// entry. The entry saves the registers before any Rust code runs, so the dump
+// shows the faulting state, and calls `exception_dispatch`.
+
+const STUB_SIZE: u64 = 16;
+
+const BREAKPOINT: u64 = 0x03;
+const PAGE_FAULT: u64 = 0x0E;
+
+//============================================================
+/// State saved by the entry stubs
+//
+//============================================================
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub registers:   Registers,                     // pushed by the common entry
+    pub vector:      u64,                           // pushed by the stub
+    pub error_code:  u64,                           // pushed by the CPU, or 0 by the stub
+    pub stack_frame: InterruptStackFrameValue,      // pushed by the CPU
+}
+
+// How the error code of an exception is printed
+enum ErrorCode {
+    None,
+    Selector,
+    Hex,
+}
+
+// One 16 bytes stub per vector: push 0 unless the CPU pushed an error code
+// (8, 10-14, 17, 21, 29, 30), push imm8, jmp to the common entry.
+// The CPU aligned RSP to 16 bytes and pushed 5 or 6 qwords, the stubs bring
+// it to 7 and the entry pushes 15: RSP is 16 bytes aligned when calling into Rust.
+global_asm!("
+    .align 16
+    .global exception_stubs
+    exception_stubs:
+    vector = 0
+    .rept 32
+        .align 16
+        .if vector != 8 && vector != 10 && vector != 11 && vector != 12 && vector != 13 && vector != 14 && vector != 17 && vector != 21 && vector != 29 && vector != 30
+            .byte 0x6a, 0
+        .endif
+        .byte 0x6a, vector
+        jmp exception_common
+        vector = vector + 1
+    .endr
+
+    exception_common:
+        pushq %r15
+        pushq %r14
+        pushq %r13
+        pushq %r12
+        pushq %r11
+        pushq %r10
+        pushq %r9
+        pushq %r8
+        pushq %rbp
+        pushq %rdi
+        pushq %rsi
+        pushq %rdx
+        pushq %rcx
+        pushq %rbx
+        pushq %rax
+
+        movq %rsp, %rdi
+        call exception_dispatch
+
+        popq %rax
+        popq %rbx
+        popq %rcx
+        popq %rdx
+        popq %rsi
+        popq %rdi
+        popq %rbp
+        popq %r8
+        popq %r9
+        popq %r10
+        popq %r11
+        popq %r12
+        popq %r13
+        popq %r14
+        popq %r15
+        addq $16, %rsp
+        iretq
+");
+
+extern "C" {
+    fn exception_stubs();
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error.set_handler_addr(stub(0x00));
+        idt.debug.set_handler_addr(stub(0x01));
+        idt.non_maskable_interrupt.set_handler_addr(stub(0x02)).set_stack_index(tss::NMI_IST_INDEX);
+        idt.breakpoint.set_handler_addr(stub(0x03));
+        idt.overflow.set_handler_addr(stub(0x04));
+        idt.bound_range_exceeded.set_handler_addr(stub(0x05));
+        idt.invalid_opcode.set_handler_addr(stub(0x06));
+        idt.device_not_available.set_handler_addr(stub(0x07));
+        idt.double_fault.set_handler_addr(stub(0x08)).set_stack_index(tss::DOUBLE_FAULT_IST_INDEX);
+        idt.coprocessor_segment_overrun.set_handler_addr(stub(0x09));
+        idt.invalid_tss.set_handler_addr(stub(0x0A));
+        idt.segment_not_present.set_handler_addr(stub(0x0B));
+        idt.stack_segment_fault.set_handler_addr(stub(0x0C));
+        idt.general_protection_fault.set_handler_addr(stub(0x0D));
+        idt.page_fault.set_handler_addr(stub(0x0E));
+        idt.x87_floating_point.set_handler_addr(stub(0x10));
+        idt.alignment_check.set_handler_addr(stub(0x11));
+        idt.machine_check.set_handler_addr(stub(0x12)).set_stack_index(tss::MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point.set_handler_addr(stub(0x13));
+        idt.virtualization.set_handler_addr(stub(0x14));
+        idt.security_exception.set_handler_addr(stub(0x1E));
+    }
+}
+
+fn stub(vector: u64) -> u64 {
+    exception_stubs as u64 + vector * STUB_SIZE
+}
+
+#[no_mangle]
+extern "C" fn exception_dispatch(frame: &mut ExceptionFrame) {
+    match frame.vector {
+        BREAKPOINT => crate::emergency_println!("EXCEPTION: BREAKPOINT\n{:#?}", frame.stack_frame),
+        PAGE_FAULT => page_fault(frame),
+        _          => fatal(frame),
+    }
+}
+
+fn page_fault(frame: &ExceptionFrame) {
+
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    let fault = PageFault {
+        address:             VirtualAddress(cpu::read_cr2()),
+        error_code,
+        instruction_pointer: frame.stack_frame.instruction_pointer,
+    };
+
+    match fault::resolve(&fault) {
+        Resolution::Resolved => {}
+        Resolution::TerminateProcess => {
+            crate::emergency_println!("\nPAGE FAULT: {} at {:?}", fault, fault.instruction_pointer);
+            process::exit(-1);
+        }
+        Resolution::Unhandled => {
+            crate::emergency_println!("\nEXCEPTION: PAGE FAULT (#PF)\nError Code: {:#?}\n{:#?}\n{:?}", error_code, frame.stack_frame, frame.registers);
+            panic!("unhandled page fault: {}, translate_addr: {:?}", fault, paging::translate_addr(fault.address));
+        }
+    }
+}
+
+//============================================================
+/// Dump the faulting context to the console and halt
+//
+//============================================================
+fn fatal(frame: &ExceptionFrame) -> ! {
+
+    let (name, error_code) = describe(frame.vector);
+
+    crate::emergency_println!("\nEXCEPTION: {}", name);
+    match error_code {
+        ErrorCode::None     => {}
+        ErrorCode::Selector => crate::emergency_println!("Error Code: {:#?}", SelectorErrorCode(frame.error_code)),
+        ErrorCode::Hex      => crate::emergency_println!("Error Code: {:#x}", frame.error_code),
+    }
+    crate::emergency_println!("{:#?}", frame.stack_frame);
+    crate::emergency_println!("{:?}", frame.registers);
+    cpu::halt()
+}
+
+// Name of an exception and the format of its error code
+fn describe(vector: u64) -> (&'static str, ErrorCode) {
+    match vector {
+        0x00 => ("DIVIDE ERROR (#DE)",              ErrorCode::None),
+        0x01 => ("DEBUG (#DB)",                     ErrorCode::None),
+        0x02 => ("NON MASKABLE INTERRUPT (NMI)",    ErrorCode::None),
+        0x04 => ("OVERFLOW (#OF)",                  ErrorCode::None),
+        0x05 => ("BOUND RANGE EXCEEDED (#BR)",      ErrorCode::None),
+        0x06 => ("INVALID OPCODE (#UD)",            ErrorCode::None),
+        0x07 => ("DEVICE NOT AVAILABLE (#NM)",      ErrorCode::None),
+        0x08 => ("DOUBLE FAULT (#DF)",              ErrorCode::Hex),
+        0x09 => ("COPROCESSOR SEGMENT OVERRUN",     ErrorCode::None),
+        0x0A => ("INVALID TSS (#TS)",               ErrorCode::Selector),
+        0x0B => ("SEGMENT NOT PRESENT (#NP)",       ErrorCode::Selector),
+        0x0C => ("STACK SEGMENT FAULT (#SS)",       ErrorCode::Selector),
+        0x0D => ("GENERAL PROTECTION FAULT (#GP)",  ErrorCode::Selector),
+        0x10 => ("X87 FLOATING POINT (#MF)",        ErrorCode::None),
+        0x11 => ("ALIGNMENT CHECK (#AC)",           ErrorCode::Hex),
+        0x12 => ("MACHINE CHECK (#MC)",             ErrorCode::None),
+        0x13 => ("SIMD FLOATING POINT (#XM)",       ErrorCode::None),
+        0x14 => ("VIRTUALIZATION (#VE)",            ErrorCode::None),
+        0x1E => ("SECURITY EXCEPTION (#SX)",        ErrorCode::Hex),
+        _    => ("RESERVED",                        ErrorCode::Hex),
+    }
+}
+
+impl fmt::Debug for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "vector {:#x} error code {:#x}\n{:#?}\n{:?}", self.vector, self.error_code, self.stack_frame, self.registers)
+    }
+}
@@ -1,5 +1,7 @@
 use core::fmt;
 use core::marker::PhantomData;
+use core::ops::Deref;
+use bitflags::bitflags;
 use crate::paging::{PhysicalAddress, VirtualAddress};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -75,12 +77,106 @@ pub type HandlerFunc =
 pub type HandlerFuncWithErrCode =
     extern "x86-interrupt" fn(&mut InterruptStackFrame, error_code: u64);
 pub type PageFaultHandlerFunc =
-    extern "x86-interrupt" fn(&mut InterruptStackFrame, error_code: u64);
+    extern "x86-interrupt" fn(&mut InterruptStackFrame, error_code: PageFaultErrorCode);
 pub type DivergingHandlerFunc =
     extern "x86-interrupt" fn(&mut InterruptStackFrame) -> !;
 pub type DivergingHandlerFuncWithErrCode =
     extern "x86-interrupt" fn(&mut InterruptStackFrame, error_code: u64) -> !;
 
+bitflags! {
+    /// Error code pushed by the CPU on a page fault (#PF)
+    #[repr(transparent)]
+    pub struct PageFaultErrorCode: u64 {
+        const PROTECTION_VIOLATION  = 1 << 0;   // 0: non-present page, 1: protection violation
+        const CAUSED_BY_WRITE       = 1 << 1;   // 0: read access, 1: write access
+        const USER_MODE             = 1 << 2;   // access originated from ring 3
+        const MALFORMED_TABLE       = 1 << 3;   // reserved bit set in a paging structure
+        const INSTRUCTION_FETCH     = 1 << 4;   // access was an instruction fetch
+        const PROTECTION_KEY        = 1 << 5;   // protection-key violation
+        const SHADOW_STACK          = 1 << 6;   // shadow-stack access
+        const SGX                   = 1 << 15;  // SGX-specific access-control violation
+    }
+}
+
+/// Error code pushed by the CPU on segment related faults (#TS, #NP, #SS, #GP)
+#[derive(Clone, Copy, PartialEq)]
+#[repr(transparent)]
+pub struct SelectorErrorCode(pub u64);
+
+impl SelectorErrorCode {
+
+    /// Exception originated from an event external to the program (e.g. hardware interrupt)
+    pub const fn external(&self) -> bool {
+        (self.0 & 0x1) != 0
+    }
+
+    /// Descriptor table referenced by the index
+    pub const fn table(&self) -> DescriptorTable {
+        match (self.0 >> 1) & 0x3 {
+            0b00 => DescriptorTable::Gdt,
+            0b01 => DescriptorTable::Idt,
+            0b10 => DescriptorTable::Ldt,
+            _    => DescriptorTable::Idt,
+        }
+    }
+
+    /// Index of the descriptor in the table
+    pub const fn index(&self) -> u64 {
+        (self.0 >> 3) & 0x1fff
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+impl EntryOptions {
+
+    //============================================================
+    //
+    //
+    //============================================================
+    pub fn set_present(&mut self, present: bool) -> &mut Self {
+        self.set_bit(15, present)
+    }
+
+    //============================================================
+    /// Interrupt gate (interrupts disabled on entry) vs trap gate
+    //
+    //============================================================
+    pub fn disable_interrupts(&mut self, disable: bool) -> &mut Self {
+        self.set_bit(8, !disable)
+    }
+
+    //============================================================
+    /// Minimum privilege level (DPL) allowed to invoke the gate with `int`
+    //
+    //============================================================
+    pub fn set_privilege_level(&mut self, dpl: u16) -> &mut Self {
+        self.0 = (self.0 & !(0x3 << 13)) | ((dpl & 0x3) << 13);
+        self
+    }
+
+    //============================================================
+    /// Switch to the given Interrupt Stack Table entry (0-6) on entry
+    ///
+    /// The caller must ensure the TSS holds a valid stack at that index.
+    //============================================================
+    pub unsafe fn set_stack_index(&mut self, index: u16) -> &mut Self {
+        debug_assert!(index < 7);
+        self.0 = (self.0 & !0x7) | (index + 1);     // 0 means "no stack switch"
+        self
+    }
+
+    fn set_bit(&mut self, bit: u16, value: bool) -> &mut Self {
+        self.0 = (self.0 & !(1 << bit)) | ((value as u16) << bit);
+        self
+    }
+}
+
 impl<F> Entry<F> {
 
     //============================================================
@@ -100,13 +196,14 @@ impl<F> Entry<F> {
     }
 
     //============================================================
-    //
-    //
+    /// Point the entry at a raw handler address (e.g. an assembly stub)
+    ///
+    /// The caller must ensure the code at `addr` is a valid interrupt handler.
     //============================================================
-    fn set_handler_addr(&mut self, addr: u64) -> &mut EntryOptions {
+    pub unsafe fn set_handler_addr(&mut self, addr: u64) -> &mut EntryOptions {
 
         let mut cs: u16 = 0;
-        unsafe { llvm_asm!("mov %cs, $0" : "=r" (cs) ) };
+        llvm_asm!("mov %cs, $0" : "=r" (cs) );
 
         self.pointer_low    = addr as u16;
         self.pointer_middle = (addr >> 16) as u16;
@@ -165,7 +262,39 @@ impl InterruptDescriptorTable {
 
 impl Entry<HandlerFunc> {
     pub fn set_handler_fn(&mut self, handler: HandlerFunc) -> &mut EntryOptions {
-        self.set_handler_addr(handler as u64)
+        unsafe { self.set_handler_addr(handler as u64) }
+    }
+}
+
+impl Entry<HandlerFuncWithErrCode> {
+    pub fn set_handler_fn(&mut self, handler: HandlerFuncWithErrCode) -> &mut EntryOptions {
+        unsafe { self.set_handler_addr(handler as u64) }
+    }
+}
+
+impl Entry<PageFaultHandlerFunc> {
+    pub fn set_handler_fn(&mut self, handler: PageFaultHandlerFunc) -> &mut EntryOptions {
+        unsafe { self.set_handler_addr(handler as u64) }
+    }
+}
+
+impl Entry<DivergingHandlerFunc> {
+    pub fn set_handler_fn(&mut self, handler: DivergingHandlerFunc) -> &mut EntryOptions {
+        unsafe { self.set_handler_addr(handler as u64) }
+    }
+}
+
+impl Entry<DivergingHandlerFuncWithErrCode> {
+    pub fn set_handler_fn(&mut self, handler: DivergingHandlerFuncWithErrCode) -> &mut EntryOptions {
+        unsafe { self.set_handler_addr(handler as u64) }
+    }
+}
+
+impl Deref for InterruptStackFrame {
+    type Target = InterruptStackFrameValue;
+
+    fn deref(&self) -> &InterruptStackFrameValue {
+        &self.value
     }
 }
 
@@ -175,6 +304,20 @@ impl fmt::Debug for InterruptStackFrame {
     }
 }
 
+impl fmt::Debug for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "SelectorErrorCode(0x0)");
+        }
+
+        let mut s = f.debug_struct("SelectorErrorCode");
+        s.field("external", &self.external());
+        s.field("table", &self.table());
+        s.field("index", &self.index());
+        s.finish()
+    }
+}
+
 impl fmt::Debug for InterruptStackFrameValue {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         struct Hex(u64);
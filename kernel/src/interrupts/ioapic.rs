@@ -0,0 +1,156 @@
+// I/O APIC
+
+use alloc::vec::Vec;
+use core::ptr;
+use crate::acpi::madt::{InterruptOverride, Madt, Polarity, TriggerMode};
+use crate::paging::{self, PhysicalAddress};
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN:    u64 = 0x10;
+
+const REG_ID:          u32 = 0x00;
+const REG_VERSION:     u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;     // 2 registers per entry
+
+const ENTRY_MASKED:          u64 = 1 << 16;
+const ENTRY_LEVEL_TRIGGERED: u64 = 1 << 15;
+const ENTRY_ACTIVE_LOW:      u64 = 1 << 13;
+
+pub struct IoApic {
+    registers:      u64,        // virtual address of IOREGSEL/IOWIN
+    pub id:         u8,
+    pub gsi_base:   u32,
+    pub gsi_count:  u32,
+}
+
+static mut IO_APICS:  Vec<IoApic> = Vec::new();
+static mut OVERRIDES: Vec<InterruptOverride> = Vec::new();
+
+impl IoApic {
+
+    //============================================================
+    //
+    //
+    //============================================================
+    fn new(id: u8, address: PhysicalAddress, gsi_base: u32) -> IoApic {
+
+        let mut io_apic = IoApic {
+            registers: paging::mmio::map(address, 0x20).0,
+            id,
+            gsi_base,
+            gsi_count: 0,
+        };
+
+        io_apic.gsi_count = ((io_apic.read(REG_VERSION) >> 16) & 0xff) + 1;
+        io_apic
+    }
+
+    //============================================================
+    //
+    //
+    //============================================================
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.registers + IOREGSEL) as *mut u32, register);
+            ptr::read_volatile((self.registers + IOWIN) as *const u32)
+        }
+    }
+
+    //============================================================
+    //
+    //
+    //============================================================
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.registers + IOREGSEL) as *mut u32, register);
+            ptr::write_volatile((self.registers + IOWIN) as *mut u32, value);
+        }
+    }
+
+    fn contains(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.gsi_count
+    }
+
+    fn redirection(&self, gsi: u32) -> u64 {
+        let register = REG_REDIRECTION + (gsi - self.gsi_base) * 2;
+        (self.read(register + 1) as u64) << 32 | self.read(register) as u64
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let register = REG_REDIRECTION + (gsi - self.gsi_base) * 2;
+        self.write(register, ENTRY_MASKED as u32);          // masked while half updated
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+//============================================================
+/// Map every I/O APIC of the MADT with all pins masked
+//
+//============================================================
+pub fn init(madt: &Madt) {
+    unsafe {
+        for info in madt.io_apics.iter() {
+            let io_apic = IoApic::new(info.id, PhysicalAddress(info.address), info.gsi_base);
+
+            for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.gsi_count {
+                io_apic.set_redirection(gsi, ENTRY_MASKED);
+            }
+
+            crate::println!("I/O APIC {}: id {:#x}, GSI {}-{}", IO_APICS.len(), io_apic.read(REG_ID) >> 24,
+                io_apic.gsi_base, io_apic.gsi_base + io_apic.gsi_count - 1);
+
+            IO_APICS.push(io_apic);
+        }
+
+        OVERRIDES = madt.overrides.clone();
+    }
+}
+
+//============================================================
+/// Route an ISA IRQ to a vector on the CPU with the given APIC id (left masked)
+//
+//============================================================
+pub fn route(irq: u8, vector: u8, destination: u32) -> bool {
+
+    let pin = isa_irq(irq);
+
+    let mut entry = (destination as u64 & 0xff) << 56 | ENTRY_MASKED | vector as u64;    // fixed, physical
+    if pin.polarity == Polarity::ActiveLow { entry |= ENTRY_ACTIVE_LOW; }
+    if pin.trigger == TriggerMode::Level   { entry |= ENTRY_LEVEL_TRIGGERED; }
+
+    match find(pin.gsi) {
+        Some(io_apic) => { io_apic.set_redirection(pin.gsi, entry); true }
+        None          => false,
+    }
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn mask(irq: u8) {
+    let gsi = isa_irq(irq).gsi;
+    if let Some(io_apic) = find(gsi) {
+        io_apic.set_redirection(gsi, io_apic.redirection(gsi) | ENTRY_MASKED);
+    }
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn unmask(irq: u8) {
+    let gsi = isa_irq(irq).gsi;
+    if let Some(io_apic) = find(gsi) {
+        io_apic.set_redirection(gsi, io_apic.redirection(gsi) & !ENTRY_MASKED);
+    }
+}
+
+fn isa_irq(irq: u8) -> InterruptOverride {
+    unsafe { OVERRIDES.iter().find(|o| o.irq == irq).copied() }.unwrap_or(InterruptOverride::identity(irq))
+}
+
+fn find(gsi: u32) -> Option<&'static IoApic> {
+    unsafe { IO_APICS.iter().find(|io_apic| io_apic.contains(gsi)) }
+}
@@ -0,0 +1,322 @@
+// INTERRUPT DISPATCH (vectors 0x20-0xFF)
+//
+// Every vector above the exceptions enters through a small assembly stub that
+// pushes its number and jumps to a common trampoline. The trampoline saves the
+// registers and calls `interrupt_dispatch`, which runs the handlers registered
+// for that vector, then acknowledges the interrupt controller.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use crate::cpu::Registers;
+use crate::sync::IrqSpinlock;
+use super::idt::{InterruptDescriptorTable, InterruptStackFrameValue};
+use crate::paging::tlb;
+use super::{apic, ioapic, pic};
+use super::syscall::SYSCALL_VECTOR;
+
+pub const FIRST_VECTOR: usize = 0x20;
+pub const VECTOR_COUNT: usize = 256 - FIRST_VECTOR;
+
+const STUB_SIZE: u64 = 16;
+
+pub type IrqHandler = fn(frame: &mut InterruptFrame, context: usize) -> IrqReturn;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IrqReturn {
+    Handled,
+    NotHandled,     // shared line, the interrupt was for another device
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HandlerId(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IrqError {
+    Exception,          // 0x00-0x1F belong to the CPU
+    Reserved,           // the system call gate or the TLB shootdown
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Controller {
+    Pic,
+    Apic,
+}
+
+//============================================================
+/// State saved by the common trampoline
+//
+//============================================================
+#[repr(C)]
+pub struct InterruptFrame {
+    pub registers:   Registers,                     // pushed by the trampoline
+    pub vector:      u64,                           // pushed by the stub (sign extended)
+    pub stack_frame: InterruptStackFrameValue,      // pushed by the CPU
+}
+
+struct Registration {
+    id:      HandlerId,
+    handler: IrqHandler,
+    context: usize,
+}
+
+lazy_static! {
+    static ref HANDLERS: Vec<IrqSpinlock<Vec<Registration>>> = (0..VECTOR_COUNT).map(|_| IrqSpinlock::new(Vec::new())).collect();
+}
+
+static NEXT_ID:   AtomicU64 = AtomicU64::new(0);
+static UNCLAIMED: AtomicU64 = AtomicU64::new(0);
+static mut CONTROLLER: Controller = Controller::Pic;
+
+// One 16 bytes stub per vector: push imm8 (sign extended), jmp to the trampoline.
+// The CPU pushed 5 qwords, the stub 1 and the trampoline 15: RSP needs 8 more
+// bytes to be 16 bytes aligned when calling into Rust.
+global_asm!("
+    .align 16
+    .global interrupt_stubs
+    interrupt_stubs:
+    vector = 0x20
+    .rept 256 - 0x20
+        .align 16
+        .byte 0x6a, vector
+        jmp interrupt_common
+        vector = vector + 1
+    .endr
+
+    interrupt_common:
+        pushq %r15
+        pushq %r14
+        pushq %r13
+        pushq %r12
+        pushq %r11
+        pushq %r10
+        pushq %r9
+        pushq %r8
+        pushq %rbp
+        pushq %rdi
+        pushq %rsi
+        pushq %rdx
+        pushq %rcx
+        pushq %rbx
+        pushq %rax
+
+        movq %rsp, %rdi
+        subq $8, %rsp
+        call interrupt_dispatch
+        addq $8, %rsp
+
+        popq %rax
+        popq %rbx
+        popq %rcx
+        popq %rdx
+        popq %rsi
+        popq %rdi
+        popq %rbp
+        popq %r8
+        popq %r9
+        popq %r10
+        popq %r11
+        popq %r12
+        popq %r13
+        popq %r14
+        popq %r15
+        addq $8, %rsp
+        iretq
+");
+
+extern "C" {
+    fn interrupt_stubs();
+}
+
+//============================================================
+/// Point every vector above the exceptions at its stub
+//
+//============================================================
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    for (i, entry) in idt.interrupts.iter_mut().enumerate() {
+        unsafe { entry.set_handler_addr(interrupt_stubs as u64 + i as u64 * STUB_SIZE); }
+    }
+}
+
+//============================================================
+/// Add a handler to a vector, lines can be shared between several handlers.
+/// Unmasks legacy IRQ lines (0x20-0x2F) on their first handler.
+/// Exceptions and the vectors the kernel keeps for itself are refused.
+//============================================================
+pub fn register_irq(vector: u8, handler: IrqHandler, context: usize) -> Result<HandlerId, IrqError> {
+
+    if (vector as usize) < FIRST_VECTOR {
+        return Err(IrqError::Exception);
+    }
+    if vector as usize == SYSCALL_VECTOR || vector == tlb::VECTOR {
+        return Err(IrqError::Reserved);
+    }
+    Ok(register(vector, handler, context))
+}
+
+//============================================================
+/// Install the kernel's own handler of a reserved vector (TLB shootdown)
+//
+//============================================================
+pub fn register_reserved(vector: u8, handler: IrqHandler, context: usize) -> HandlerId {
+    assert!(vector == tlb::VECTOR, "vector {:#x} is not reserved for the kernel", vector);
+    register(vector, handler, context)
+}
+
+fn register(vector: u8, handler: IrqHandler, context: usize) -> HandlerId {
+
+    let id = HandlerId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+
+    let first = {
+        let mut handlers = HANDLERS[vector as usize - FIRST_VECTOR].lock();
+        handlers.push(Registration { id, handler, context });
+        handlers.len() == 1
+    };
+
+    if let (true, Some(irq)) = (first, line(vector)) {
+        unmask(irq);
+    }
+    id
+}
+
+//============================================================
+/// Remove a handler, masks legacy IRQ lines left without handlers.
+/// Must not be called from the handler itself.
+//============================================================
+pub fn unregister_irq(vector: u8, id: HandlerId) -> bool {
+
+    let (removed, empty) = {
+        let mut handlers = HANDLERS[vector as usize - FIRST_VECTOR].lock();
+        let before = handlers.len();
+        handlers.retain(|registration| registration.id != id);
+        (handlers.len() != before, handlers.is_empty())
+    };
+
+    if let (true, true, Some(irq)) = (removed, empty, line(vector)) {
+        mask(irq);
+    }
+    removed
+}
+
+//============================================================
+/// Vector of a legacy IRQ line (same with the 8259 and the I/O APIC)
+//
+//============================================================
+pub const fn line_vector(irq: u8) -> u8 {
+    pic::PIC1_OFFSET + irq
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn controller() -> Controller {
+    unsafe { CONTROLLER }
+}
+
+//============================================================
+/// Move every line from the 8259 to the I/O APIC (same vectors),
+/// delivered to the current CPU
+//
+//============================================================
+pub fn switch_to_apic() {
+
+    pic::disable();
+
+    unsafe { CONTROLLER = Controller::Apic; }
+
+    for irq in 0..pic::IRQ_COUNT as u8 {
+        ioapic::route(irq, line_vector(irq), apic::id());
+        if has_handlers(line_vector(irq)) {
+            ioapic::unmask(irq);
+        }
+    }
+}
+
+//============================================================
+/// Deliver an IRQ line to the CPU with the given APIC id
+//
+//============================================================
+pub fn set_destination(irq: u8, apic_id: u32) -> bool {
+
+    if controller() != Controller::Apic {
+        return false;   // the 8259 only delivers to the bootstrap processor
+    }
+
+    let routed = ioapic::route(irq, line_vector(irq), apic_id);
+    if routed && has_handlers(line_vector(irq)) {
+        ioapic::unmask(irq);
+    }
+    routed
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn mask(irq: u8) {
+    match controller() {
+        Controller::Pic  => pic::mask(irq),
+        Controller::Apic => ioapic::mask(irq),
+    }
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn unmask(irq: u8) {
+    match controller() {
+        Controller::Pic  => pic::unmask(irq),
+        Controller::Apic => ioapic::unmask(irq),
+    }
+}
+
+//============================================================
+/// Interrupts no handler claimed (spurious or misrouted)
+//
+//============================================================
+pub fn unclaimed_count() -> u64 {
+    UNCLAIMED.load(Ordering::Relaxed)
+}
+
+// Legacy IRQ line of a vector
+fn line(vector: u8) -> Option<u8> {
+    match vector.wrapping_sub(pic::PIC1_OFFSET) {
+        irq if (irq as usize) < pic::IRQ_COUNT => Some(irq),
+        _ => None,
+    }
+}
+
+fn has_handlers(vector: u8) -> bool {
+    !HANDLERS[vector as usize - FIRST_VECTOR].lock().is_empty()
+}
+
+#[no_mangle]
+extern "C" fn interrupt_dispatch(frame: &mut InterruptFrame) {
+
+    let vector = frame.vector as u8;
+    let irq    = line(vector);
+
+    if let (Controller::Pic, Some(irq)) = (controller(), irq) {
+        if pic::is_spurious(irq) {
+            return;
+        }
+    }
+
+    // every handler of a shared line gets a chance, more than one device may be asserting it
+    let mut handled = false;
+    for registration in HANDLERS[vector as usize - FIRST_VECTOR].lock().iter() {
+        handled |= (registration.handler)(frame, registration.context) == IrqReturn::Handled;
+    }
+
+    if !handled {
+        UNCLAIMED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    match (controller(), irq) {
+        (Controller::Pic, Some(irq))                            => pic::end_of_interrupt(irq),
+        (Controller::Apic, _) if vector != apic::SPURIOUS_VECTOR => apic::eoi(),
+        _ => {}
+    }
+}
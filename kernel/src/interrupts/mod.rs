@@ -1,22 +1,64 @@
-mod idt;
+pub mod idt;
+mod exceptions;
+mod syscall;
+pub mod pic;
+pub mod irq;
+pub mod apic;
+pub mod ioapic;
 use core::mem;
+use crate::acpi;
 use crate::cpu::gdt::{DescriptorTablePointer};
-use idt::{InterruptDescriptorTable, InterruptStackFrame};
+use spin::Once;
+use idt::{InterruptDescriptorTable};
 
-pub static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
+// Built once, handlers are added through `irq::register_irq` afterwards
+static IDT: Once<InterruptDescriptorTable> = Once::new();
 
 pub fn initialize() {
 
-    unsafe { IDT.breakpoint.set_handler_fn(breakpoint_handler); }
+    let idt = IDT.call_once(|| {
+        let mut idt = InterruptDescriptorTable::new();
+        exceptions::install(&mut idt);
+        irq::install(&mut idt);
+        syscall::install(&mut idt);     // replaces the 0x80 stub
+        idt
+    });
+
+    pic::init();
 
     let ptr = DescriptorTablePointer {
-        base: unsafe { (&IDT) as *const _ as u64 },
+        base: idt as *const _ as u64,
         limit: (mem::size_of::<InterruptDescriptorTable>() - 1) as u16,
     };
 
     unsafe { llvm_asm!("lidt ($0)" :: "r" (&ptr) : "memory"); }
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    crate::println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+//============================================================
+/// Switch from the 8259 to the local APIC and I/O APIC (needs the heap)
+//
+//============================================================
+pub fn initialize_apic() -> bool {
+
+    if !apic::is_supported() {
+        return false;
+    }
+
+    let madt = match acpi::madt::parse() {
+        Some(madt) => madt,
+        None       => return false,
+    };
+
+    crate::println!("ACPI MADT: {} processor(s), {} I/O APIC(s), {} override(s)",
+        madt.processors.len(), madt.io_apics.len(), madt.overrides.len());
+
+    if madt.io_apics.is_empty() {
+        return false;
+    }
+
+    apic::init(&madt);
+    ioapic::init(&madt);
+    irq::switch_to_apic();
+
+    true
 }
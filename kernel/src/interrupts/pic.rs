@@ -0,0 +1,116 @@
+// LEGACY 8259 PROGRAMMABLE INTERRUPT CONTROLLERS (master/slave)
+
+use crate::cpu::io::{inb, outb, io_wait};
+
+pub const PIC1_OFFSET: u8 = 0x20;      // IRQ0-7  -> 0x20-0x27
+pub const PIC2_OFFSET: u8 = 0x28;      // IRQ8-15 -> 0x28-0x2F
+
+pub const IRQ_COUNT: usize = 16;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA:    u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xA0;
+const PIC2_DATA:    u16 = 0xA1;
+
+const ICW1_INIT:    u8 = 0x10;
+const ICW1_ICW4:    u8 = 0x01;
+const ICW4_8086:    u8 = 0x01;
+const OCW3_READ_ISR: u8 = 0x0B;
+const EOI:          u8 = 0x20;
+
+const CASCADE_IRQ:  u8 = 2;
+
+//============================================================
+/// Remap IRQ0-15 above the exception vectors, all lines masked
+//
+//============================================================
+pub fn init() {
+    unsafe {
+        outb(PIC1_COMMAND, ICW1_INIT | ICW1_ICW4); io_wait();
+        outb(PIC2_COMMAND, ICW1_INIT | ICW1_ICW4); io_wait();
+        outb(PIC1_DATA, PIC1_OFFSET); io_wait();           // ICW2: vector offset
+        outb(PIC2_DATA, PIC2_OFFSET); io_wait();
+        outb(PIC1_DATA, 1 << CASCADE_IRQ); io_wait();      // ICW3: slave on IRQ2
+        outb(PIC2_DATA, CASCADE_IRQ); io_wait();           //       slave identity
+        outb(PIC1_DATA, ICW4_8086); io_wait();
+        outb(PIC2_DATA, ICW4_8086); io_wait();
+
+        outb(PIC1_DATA, !(1 << CASCADE_IRQ));              // everything masked but the cascade
+        outb(PIC2_DATA, 0xff);
+    }
+}
+
+//============================================================
+/// Mask every line (when switching to the APIC)
+//
+//============================================================
+pub fn disable() {
+    unsafe {
+        outb(PIC1_DATA, 0xff);
+        outb(PIC2_DATA, 0xff);
+    }
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn mask(irq: u8) {
+    let (port, bit) = line(irq);
+    unsafe { outb(port, inb(port) | (1 << bit)); }
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn unmask(irq: u8) {
+    let (port, bit) = line(irq);
+    unsafe { outb(port, inb(port) & !(1 << bit)); }
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn end_of_interrupt(irq: u8) {
+    unsafe {
+        if irq >= 8 {
+            outb(PIC2_COMMAND, EOI);
+        }
+        outb(PIC1_COMMAND, EOI);
+    }
+}
+
+//============================================================
+/// IRQ7/IRQ15 fire spuriously when a request goes away before it is acknowledged:
+/// the line is then not set in the In-Service Register and must not get an EOI
+/// (except for the cascade on the master when it comes from the slave).
+//============================================================
+pub fn is_spurious(irq: u8) -> bool {
+    unsafe {
+        match irq {
+            7 => {
+                outb(PIC1_COMMAND, OCW3_READ_ISR);
+                inb(PIC1_COMMAND) & 0x80 == 0
+            }
+            15 => {
+                outb(PIC2_COMMAND, OCW3_READ_ISR);
+                let spurious = inb(PIC2_COMMAND) & 0x80 == 0;
+                if spurious {
+                    outb(PIC1_COMMAND, EOI);
+                }
+                spurious
+            }
+            _ => false,
+        }
+    }
+}
+
+fn line(irq: u8) -> (u16, u8) {
+    debug_assert!((irq as usize) < IRQ_COUNT);
+    match irq {
+        0..=7 => (PIC1_DATA, irq),
+        _     => (PIC2_DATA, irq - 8),
+    }
+}
@@ -0,0 +1,78 @@
+use crate::cpu::Registers;
+use crate::syscall;
+use super::idt::{InterruptDescriptorTable, InterruptStackFrameValue};
+
+pub const SYSCALL_VECTOR: usize = 0x80;
+
+//============================================================
+/// User state saved by the `int 0x80` entry stub
+//
+//============================================================
+#[repr(C)]
+pub struct SyscallFrame {
+    pub registers:   Registers,                     // pushed by the entry stub
+    pub stack_frame: InterruptStackFrameValue,      // pushed by the CPU
+}
+
+// The CPU aligns RSP to 16 bytes and pushes 5 qwords, the stub pushes 15 more:
+// RSP is 16 bytes aligned again when calling into Rust.
+global_asm!("
+    .global syscall_interrupt_entry
+    syscall_interrupt_entry:
+        pushq %r15
+        pushq %r14
+        pushq %r13
+        pushq %r12
+        pushq %r11
+        pushq %r10
+        pushq %r9
+        pushq %r8
+        pushq %rbp
+        pushq %rdi
+        pushq %rsi
+        pushq %rdx
+        pushq %rcx
+        pushq %rbx
+        pushq %rax
+
+        movq %rsp, %rdi
+        call syscall_interrupt_dispatch
+
+        popq %rax
+        popq %rbx
+        popq %rcx
+        popq %rdx
+        popq %rsi
+        popq %rdi
+        popq %rbp
+        popq %r8
+        popq %r9
+        popq %r10
+        popq %r11
+        popq %r12
+        popq %r13
+        popq %r14
+        popq %r15
+        iretq
+");
+
+extern "C" {
+    fn syscall_interrupt_entry();
+}
+
+//============================================================
+/// Install the `int 0x80` gate, callable from ring 3
+//
+//============================================================
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.interrupts[SYSCALL_VECTOR - 32]
+            .set_handler_addr(syscall_interrupt_entry as u64)
+            .set_privilege_level(3);
+    }
+}
+
+#[no_mangle]
+extern "C" fn syscall_interrupt_dispatch(frame: &mut SyscallFrame) {
+    syscall::dispatch(&mut frame.registers);
+}
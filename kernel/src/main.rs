@@ -1,4 +1,5 @@
 #![feature(llvm_asm)]
+#![feature(global_asm)]
 #![feature(alloc_error_handler)] // at the top of the file
 #![feature(const_fn)]
 #![feature(const_fn_fn_ptr_basics)]
@@ -14,13 +15,19 @@ use alloc::{boxed::Box, vec::Vec};  //rc::Rc, vec,
 use core::panic::PanicInfo;
 use bootloader::BootInfo;
 
+mod acpi;
 mod cpu;
 mod console;
+mod debug;
 mod interrupts;
 mod ktty;
 mod paging;
 mod memory;
 mod heap;
+mod process;
+mod sync;
+mod syscall;
+mod time;
 
 #[no_mangle]
 pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
@@ -29,24 +36,49 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
     println!("DUMP {:#?}", boot_info);
     println!("\n========================================\n");
 
-    println!("Loading IDT...");
-    interrupts::initialize();
     println!("Loading GDT (replacing trampoline)...");
     cpu::gdt::init();
+    println!("Loading IDT...");
+    interrupts::initialize();
+    println!("Enabling syscall/sysret...");
+    syscall::fast::init();
 
     println!("Initializing Frame Allocator...");
     memory::FrameAllocator::init(boot_info);
 
     println!("Initializing Paging...");
     let mut mapper = paging::Mapper::new();
+    memory::context::init();
 
     println!("Initializing Heap Allocator...");
     heap::HeapAllocator::init();
+    heap::selftest::run();
+
+    println!("Registering page fault resolvers...");
+    memory::fault::init();
+    memory::selftest::run();
+
+    println!("Initializing APIC...");
+    if !interrupts::initialize_apic() {
+        println!("APIC not available, staying on the 8259 PIC");
+    }
+    memory::FrameAllocator::reclaim_acpi_memory();     // tables parsed
+    paging::tlb::init();
+    cpu::set_online();
+
+    println!("Enabling interrupts...");
+    cpu::enable_interrupts();
+
+    println!("Starting system timer...");
+    time::init();
 
     println!("\nTesting int3...\n");
     unsafe { llvm_asm!("int3"); }
     println!("Testing int3... SURVIVED!");
 
+    println!();
+    debug::command("heap");
+
     println!("\nBYE...");
 
     loop {}
@@ -54,8 +86,8 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("\n{}", info);
-    loop {}
+    emergency_println!("\n{}", info);     // the console lock may be held by the panicking code
+    cpu::halt()
 }
 
 #[alloc_error_handler]
@@ -1,4 +1,420 @@
+// ADDRESS SPACES
+//
+// Every context has its own PML4. The first slot (512 GiB) is user space and
+// private to the context, all the other slots point to the kernel's P3 tables
+// and are shared: a kernel mapping made from any context shows up in all of them.
+// What user space may contain is described by the context's areas (see vma.rs).
+// Pages of PROT_NONE areas keep their frame in a non-present entry, so leaf
+// entries are in use whenever they are not zero, present or not.
 
-struct Context {
-    
+use alloc::vec::Vec;
+use core::ptr;
+use crate::cpu;
+use crate::paging::{tlb, MapError, Mapper, PageTable, PageTableFlags, PhysicalAddress, VirtualAddress, PHYSICAL_MEMORY_OFFSET};
+use crate::paging::{PAGE_SIZE_4K, PAGE_SIZE_2M, PAGE_SIZE_1G};
+use super::FrameAllocator;
+use super::fault::COPY_ON_WRITE;
+use super::vma::{Backing, Protection, Vma, VmaError, VmaTree};
+
+pub const USER_SPACE_START: u64 = 0x0000_0000_0000_1000;   // the null page stays unmapped
+pub const USER_SPACE_END:   u64 = 0x0000_0080_0000_0000;
+
+/// Software bit of pages shared on purpose: stay writable across fork
+pub const SHARED: PageTableFlags = PageTableFlags::BIT_10;
+
+/// Software bit of pages whose frame is not RAM from the frame allocator (MMIO)
+pub const DEVICE: PageTableFlags = PageTableFlags::BIT_11;
+
+const USER_SLOTS: usize = (USER_SPACE_END >> 39) as usize;
+
+// PML4 the bootloader built, template for the kernel slots
+static mut KERNEL_P4: u64 = 0;
+
+pub struct Context {
+    p4:   PhysicalAddress,
+    vmas: VmaTree,
+}
+
+//============================================================
+/// Give every kernel slot a P3 table now, so that no kernel mapping made
+/// later needs a new PML4 entry (contexts only copy the PML4 at creation)
+//============================================================
+pub fn init() {
+
+    let p4 = cpu::read_cr3();
+    let table = unsafe { &mut *((PHYSICAL_MEMORY_OFFSET + p4) as *mut PageTable) };
+
+    for entry in table.entries[USER_SLOTS..].iter_mut().filter(|entry| entry.is_unused()) {
+        let frame = FrameAllocator::allocate_frame().expect("no frame for the kernel page tables");
+        entry.set(PhysicalAddress(frame), PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+
+    unsafe { KERNEL_P4 = p4; }
+}
+
+impl Context {
+
+    //============================================================
+    /// Empty user space, shared kernel space
+    //
+    //============================================================
+    pub fn new() -> Option<Context> {
+
+        let frame = FrameAllocator::allocate_frame()?;     // zeroed: no user mappings
+
+        unsafe {
+            assert!(KERNEL_P4 != 0, "memory::context::init not called");
+            let kernel = &*((PHYSICAL_MEMORY_OFFSET + KERNEL_P4) as *const PageTable);
+            let table  = &mut *((PHYSICAL_MEMORY_OFFSET + frame) as *mut PageTable);
+            ptr::copy_nonoverlapping(
+                kernel.entries[USER_SLOTS..].as_ptr(),
+                table.entries[USER_SLOTS..].as_mut_ptr(),
+                512 - USER_SLOTS);
+        }
+
+        Some(Context { p4: PhysicalAddress(frame), vmas: VmaTree::new() })
+    }
+
+    //============================================================
+    //
+    //
+    //============================================================
+    pub fn p4(&self) -> PhysicalAddress {
+        self.p4
+    }
+
+    //============================================================
+    //
+    //
+    //============================================================
+    pub fn vmas(&self) -> &VmaTree {
+        &self.vmas
+    }
+
+    //============================================================
+    /// Edit this context's tables, active or not
+    //
+    //============================================================
+    pub fn mapper(&mut self) -> Mapper {
+        unsafe { Mapper::for_table(self.p4) }
+    }
+
+    //============================================================
+    /// Map a user page, the frame now belongs to the context (freed on drop).
+    /// It stays the caller's when no page table could be allocated.
+    //============================================================
+    pub fn map(&mut self, page: u64, frame: u64, flags: PageTableFlags) -> Result<(), MapError> {
+
+        assert!(is_user_address(page), "page {:#x} outside user space", page);
+
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER;
+        let mut mapper = self.mapper();
+        let previous = mapper.entry_mut(page)
+            .filter(|entry| !entry.is_unused())
+            .map(|entry| (entry.address().0, entry.flags()));
+
+        mapper.map_to(page, frame, flags)?;     // invalidates when replacing
+        if let Some((frame, flags)) = previous {
+            release(frame, flags);
+        }
+        Ok(())
+    }
+
+    //============================================================
+    /// Back a user page with a new zeroed frame
+    //
+    //============================================================
+    pub fn allocate(&mut self, page: u64, flags: PageTableFlags) -> bool {
+
+        let frame = match FrameAllocator::allocate_frame() {
+            Some(frame) => frame,
+            None        => return false,
+        };
+
+        match self.map(page, frame, flags) {
+            Ok(())  => true,
+            Err(_)  => { FrameAllocator::deallocate_frame(frame); false }
+        }
+    }
+
+    //============================================================
+    /// Remove a user page and free its frame
+    //
+    //============================================================
+    pub fn unmap(&mut self, page: u64) -> bool {
+
+        assert!(is_user_address(page), "page {:#x} outside user space", page);
+
+        let (frame, flags) = match self.mapper().entry_mut(page) {
+            Some(entry) if !entry.is_unused() => {
+                let mapped = (entry.address().0, entry.flags());
+                entry.set_unused();
+                mapped
+            }
+            _ => return false,
+        };
+
+        tlb::shootdown(VirtualAddress(page));     // before the frame can be reused
+        release(frame, flags);
+        true
+    }
+
+    //============================================================
+    /// Reserve an area of `size` bytes, at `address` or anywhere if None.
+    /// Nothing is mapped until the first access. Returns the start.
+    //============================================================
+    pub fn mmap(&mut self, address: Option<u64>, size: u64, protection: Protection, backing: Backing) -> Result<u64, VmaError> {
+
+        let size = size.checked_add(PAGE_SIZE_4K - 1).ok_or(VmaError::InvalidRange)? & !(PAGE_SIZE_4K - 1);
+        if size == 0 {
+            return Err(VmaError::InvalidRange);
+        }
+
+        let start = match address {
+            Some(address) => address,
+            None          => self.vmas.find_free(size)?,
+        };
+        let end = start.checked_add(size).ok_or(VmaError::InvalidRange)?;
+
+        self.vmas.insert(Vma { start, end, protection, backing })?;
+        Ok(start)
+    }
+
+    //============================================================
+    /// Drop [start, start + size) from the areas and free its pages
+    //
+    //============================================================
+    pub fn munmap(&mut self, start: u64, size: u64) -> Result<(), VmaError> {
+
+        let end = start.checked_add(size).ok_or(VmaError::InvalidRange)?;
+        let removed = self.vmas.remove(start, end)?;
+
+        let mut mapper = self.mapper();
+        let mut frames = Vec::new();
+
+        for page in pages(&removed) {
+            if let Some(entry) = mapper.entry_mut(page).filter(|entry| !entry.is_unused()) {
+                frames.push((entry.address().0, entry.flags()));
+                entry.set_unused();
+            }
+        }
+
+        // no CPU may still reach a frame once it is reused
+        tlb::shootdown_all();
+
+        for (frame, flags) in frames {
+            release(frame, flags);
+        }
+        Ok(())
+    }
+
+    //============================================================
+    /// Change the rights of [start, start + size), which must be all areas.
+    /// Pages still shared copy-on-write only get write access on the next fault,
+    /// PROT_NONE pages are made non-present until their rights come back.
+    //============================================================
+    pub fn mprotect(&mut self, start: u64, size: u64, protection: Protection) -> Result<(), VmaError> {
+
+        let end = start.checked_add(size).ok_or(VmaError::InvalidRange)?;
+        let updated = self.vmas.protect(start, end, protection)?;
+
+        let mut mapper = self.mapper();
+        for page in pages(&updated) {
+            let entry = match mapper.entry_mut(page) {
+                Some(entry) if !entry.is_unused() => entry,
+                _ => continue,
+            };
+
+            let mut flags = (entry.flags() | PageTableFlags::PRESENT) - PageTableFlags::WRITABLE - COPY_ON_WRITE - PageTableFlags::NO_EXECUTE;
+
+            if protection.contains(Protection::WRITE) {
+                let private = flags.intersects(SHARED | DEVICE) || FrameAllocator::reference_count(entry.address().0) == 1;
+                flags |= if private { PageTableFlags::WRITABLE } else { COPY_ON_WRITE };
+            }
+            if !protection.contains(Protection::EXECUTE) {
+                flags |= PageTableFlags::NO_EXECUTE;
+            }
+            if protection.is_empty() {
+                flags -= PageTableFlags::PRESENT;     // any access faults, the frame stays recorded
+            }
+            entry.set_flags(flags);
+        }
+
+        tlb::shootdown_all();
+        Ok(())
+    }
+
+    //============================================================
+    /// Clone the user space. Pages are shared copy-on-write: writable ones
+    /// become read-only in both contexts until the first write copies them.
+    /// SHARED and DEVICE pages stay shared, huge pages are copied right away.
+    //============================================================
+    pub fn fork(&mut self) -> Option<Context> {
+
+        let mut child = Context::new()?;
+        let shared = self.share_pages(&mut child.mapper());
+
+        // the parent lost write access to its pages, even if the copy failed
+        tlb::shootdown_all();
+
+        shared?;    // out of frames: dropping the child gives back what it got
+        child.vmas = self.vmas.clone();
+        Some(child)
+    }
+
+    // Map every user page into the tables of a new child (see `fork`)
+    fn share_pages(&self, mapper: &mut Mapper) -> Option<()> {
+
+        let p4 = table_mut(self.p4.0);
+
+        for (index4, entry4) in p4.entries[..USER_SLOTS].iter().enumerate().filter(|(_, e)| e.is_present()) {
+            let p3 = table_mut(entry4.address().0);
+
+            for (index3, entry3) in p3.entries.iter().enumerate().filter(|(_, e)| e.is_present()) {
+                let page3 = (index4 as u64) << 39 | (index3 as u64) << 30;
+                if entry3.is_huge() {
+                    let frame = copy_huge(entry3.huge_address(PAGE_SIZE_1G).0, PAGE_SIZE_1G)?;
+                    if mapper.map_huge_1g(page3, frame, entry3.flags()).is_err() {
+                        free_huge(frame, PAGE_SIZE_1G);
+                        return None;
+                    }
+                    continue;
+                }
+                let p2 = table_mut(entry3.address().0);
+
+                for (index2, entry2) in p2.entries.iter().enumerate().filter(|(_, e)| e.is_present()) {
+                    let page2 = page3 | (index2 as u64) << 21;
+                    if entry2.is_huge() {
+                        let frame = copy_huge(entry2.huge_address(PAGE_SIZE_2M).0, PAGE_SIZE_2M)?;
+                        if mapper.map_huge_2m(page2, frame, entry2.flags()).is_err() {
+                            free_huge(frame, PAGE_SIZE_2M);
+                            return None;
+                        }
+                        continue;
+                    }
+                    let p1 = table_mut(entry2.address().0);
+
+                    for (index1, entry1) in p1.entries.iter_mut().enumerate().filter(|(_, e)| !e.is_unused()) {
+                        let mut flags = entry1.flags();
+                        if !flags.intersects(SHARED | DEVICE) && flags.intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE) {
+                            flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                            entry1.set_flags(flags);
+                        }
+
+                        if !flags.contains(DEVICE) {
+                            FrameAllocator::share_frame(entry1.address().0);
+                        }
+                        if mapper.map_to(page2 | (index1 as u64) << 12, entry1.address().0, flags).is_err() {
+                            release(entry1.address().0, flags);     // the share taken above
+                            return None;
+                        }
+                    }
+                }
+            }
+        }
+        Some(())
+    }
+
+    //============================================================
+    /// Load CR3 with this context
+    //
+    //============================================================
+    pub fn activate(&self) {
+        if !self.is_active() {
+            unsafe { cpu::write_cr3(self.p4.0); }
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        cpu::read_cr3() == self.p4.0
+    }
+}
+
+impl Drop for Context {
+
+    //============================================================
+    /// Free every user frame and table, then the PML4 (kernel slots are shared)
+    //
+    //============================================================
+    fn drop(&mut self) {
+
+        assert!(!self.is_active(), "dropping the active address space");
+
+        let p4 = table(self.p4.0);
+
+        for entry4 in p4.entries[..USER_SLOTS].iter().filter(|e| e.is_present()) {
+            let p3 = table(entry4.address().0);
+
+            for entry3 in p3.entries.iter().filter(|e| e.is_present()) {
+                if entry3.is_huge() {
+                    free_huge(entry3.huge_address(PAGE_SIZE_1G).0, PAGE_SIZE_1G);
+                    continue;
+                }
+                let p2 = table(entry3.address().0);
+
+                for entry2 in p2.entries.iter().filter(|e| e.is_present()) {
+                    if entry2.is_huge() {
+                        free_huge(entry2.huge_address(PAGE_SIZE_2M).0, PAGE_SIZE_2M);
+                        continue;
+                    }
+                    let p1 = table(entry2.address().0);
+
+                    for entry1 in p1.entries.iter().filter(|e| !e.is_unused()) {
+                        release(entry1.address().0, entry1.flags());
+                    }
+                    FrameAllocator::deallocate_frame(entry2.address().0);
+                }
+                FrameAllocator::deallocate_frame(entry3.address().0);
+            }
+            FrameAllocator::deallocate_frame(entry4.address().0);
+        }
+
+        FrameAllocator::deallocate_frame(self.p4.0);
+    }
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn is_user_address(address: u64) -> bool {
+    address >= USER_SPACE_START && address < USER_SPACE_END
+}
+
+// Give back the frame of a removed leaf, device memory is not ours
+fn release(frame: u64, flags: PageTableFlags) {
+    if !flags.contains(DEVICE) {
+        FrameAllocator::deallocate_frame(frame);
+    }
+}
+
+// Every page of some areas
+fn pages(areas: &[Vma]) -> impl Iterator<Item = u64> + '_ {
+    areas.iter().flat_map(|vma| (vma.start..vma.end).step_by(PAGE_SIZE_4K as usize))
+}
+
+fn table(address: u64) -> &'static PageTable {
+    unsafe { &*((PHYSICAL_MEMORY_OFFSET + address) as *const PageTable) }
+}
+
+fn table_mut(address: u64) -> &'static mut PageTable {
+    unsafe { &mut *((PHYSICAL_MEMORY_OFFSET + address) as *mut PageTable) }
+}
+
+// Private copy of a huge page, None when no contiguous run is left
+fn copy_huge(address: u64, size: u64) -> Option<u64> {
+
+    let frame = FrameAllocator::allocate_frames(size / PAGE_SIZE_4K, size)?;
+    unsafe {
+        ptr::copy_nonoverlapping(
+            (PHYSICAL_MEMORY_OFFSET + address) as *const u8,
+            (PHYSICAL_MEMORY_OFFSET + frame) as *mut u8,
+            size as usize);
+    }
+    Some(frame)
+}
+
+// Frames of a huge page (address from `huge_address`)
+fn free_huge(address: u64, size: u64) {
+    FrameAllocator::deallocate_frames(address, size / PAGE_SIZE_4K);
 }
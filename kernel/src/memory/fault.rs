@@ -0,0 +1,225 @@
+// PAGE FAULT RESOLUTION
+//
+// The page fault handler asks every registered resolver in turn whether it can
+// fix the faulting access. Unresolved user faults terminate the process,
+// unresolved kernel faults panic.
+
+use alloc::vec::Vec;
+use core::{fmt, ptr};
+use crate::interrupts::idt::PageFaultErrorCode;
+use crate::paging::{tlb, Mapper, PageTableFlags, PhysicalAddress, VirtualAddress, PHYSICAL_MEMORY_OFFSET};
+use super::FrameAllocator;
+
+/// Software bit of write protected pages that get a private copy on the first write
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    Unhandled,              // not for this resolver, ask the next one
+    Resolved,               // mapping fixed, retry the access
+    TerminateProcess,       // invalid user access
+}
+
+pub type FaultResolver = fn(&PageFault) -> Resolution;
+
+pub struct PageFault {
+    pub address:             VirtualAddress,    // CR2
+    pub error_code:          PageFaultErrorCode,
+    pub instruction_pointer: VirtualAddress,
+}
+
+struct DemandZeroRegion {
+    start: u64,
+    end:   u64,
+    flags: PageTableFlags,
+}
+
+static mut RESOLVERS:           Vec<FaultResolver>    = Vec::new();
+static mut DEMAND_ZERO_REGIONS: Vec<DemandZeroRegion> = Vec::new();
+
+impl PageFault {
+
+    pub fn is_present(&self) -> bool {
+        self.error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+    }
+
+    pub fn is_user(&self) -> bool {
+        self.error_code.contains(PageFaultErrorCode::USER_MODE)
+    }
+
+    pub fn is_reserved(&self) -> bool {
+        self.error_code.contains(PageFaultErrorCode::MALFORMED_TABLE)
+    }
+
+    pub fn access(&self) -> Access {
+        if self.error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            Access::Execute
+        } else if self.error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            Access::Write
+        } else {
+            Access::Read
+        }
+    }
+
+    pub fn page(&self) -> u64 {
+        self.address.0 & !0xfff
+    }
+}
+
+//============================================================
+/// Register the built-in resolvers (needs the heap)
+//
+//============================================================
+pub fn init() {
+    register(copy_on_write);
+    register(super::vma::resolve_fault);
+    register(demand_zero);
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn register(resolver: FaultResolver) {
+    unsafe { RESOLVERS.push(resolver); }
+}
+
+//============================================================
+/// Back a range with zeroed frames on first access
+//
+//============================================================
+pub fn add_demand_zero_region(start: VirtualAddress, end: VirtualAddress, flags: PageTableFlags) {
+    unsafe { DEMAND_ZERO_REGIONS.push(DemandZeroRegion { start: start.0, end: end.0, flags }); }
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn resolve(fault: &PageFault) -> Resolution {
+
+    if fault.is_reserved() {
+        return Resolution::Unhandled;   // corrupted page tables, nothing to fix
+    }
+
+    for resolver in unsafe { RESOLVERS.iter() } {
+        match resolver(fault) {
+            Resolution::Unhandled => continue,
+            resolution            => return resolution,
+        }
+    }
+
+    match fault.is_user() {
+        true  => Resolution::TerminateProcess,
+        false => Resolution::Unhandled,
+    }
+}
+
+//============================================================
+/// Write to a present COPY_ON_WRITE page: give it a private writable copy
+//
+//============================================================
+fn copy_on_write(fault: &PageFault) -> Resolution {
+
+    if !fault.is_present() || fault.access() != Access::Write {
+        return Resolution::Unhandled;
+    }
+
+    let mut mapper = Mapper::new();
+    let entry = match mapper.entry_mut(fault.page()) {
+        Some(entry) if entry.is_present() && entry.flags().contains(COPY_ON_WRITE) => entry,
+        _ => return Resolution::Unhandled,
+    };
+
+    if fault.is_user() && !entry.flags().contains(PageTableFlags::USER) {
+        return Resolution::Unhandled;
+    }
+
+    let shared = entry.address().0;
+    let flags  = (entry.flags() - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+    // the other owners already made their copy: keep the frame
+    if FrameAllocator::reference_count(shared) == 1 {
+        entry.set_flags(flags);
+        tlb::shootdown(VirtualAddress(fault.page()));
+        return Resolution::Resolved;
+    }
+
+    let frame = match FrameAllocator::allocate_frame() {
+        Some(frame) => frame,
+        None        => return Resolution::Unhandled,
+    };
+
+    unsafe {
+        ptr::copy_nonoverlapping(
+            (PHYSICAL_MEMORY_OFFSET + shared) as *const u8,
+            (PHYSICAL_MEMORY_OFFSET + frame) as *mut u8,
+            4096);
+    }
+
+    entry.set(PhysicalAddress(frame), flags);
+    tlb::shootdown(VirtualAddress(fault.page()));
+
+    FrameAllocator::deallocate_frame(shared);     // one owner less
+
+    Resolution::Resolved
+}
+
+//============================================================
+/// Access to a non-present page of a demand-zero region
+//
+//============================================================
+fn demand_zero(fault: &PageFault) -> Resolution {
+
+    if fault.is_present() {
+        return Resolution::Unhandled;
+    }
+
+    let address = fault.address.0;
+    let region = unsafe { DEMAND_ZERO_REGIONS.iter().find(|r| address >= r.start && address < r.end) };
+
+    let flags = match region {
+        Some(region) => region.flags,
+        None         => return Resolution::Unhandled,
+    };
+
+    if (fault.is_user() && !flags.contains(PageTableFlags::USER))
+        || (fault.access() == Access::Write && !flags.contains(PageTableFlags::WRITABLE)) {
+        return Resolution::Unhandled;
+    }
+
+    let frame = match FrameAllocator::allocate_frame() {   // zeroed
+        Some(frame) => frame,
+        None        => return Resolution::Unhandled,
+    };
+
+    match Mapper::new().map_to(fault.page(), frame, flags | PageTableFlags::PRESENT) {
+        Ok(())  => Resolution::Resolved,
+        Err(_)  => { FrameAllocator::deallocate_frame(frame); Resolution::Unhandled }
+    }
+}
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = if self.is_user() { "user" } else { "kernel" };
+        let access = match self.access() {
+            Access::Read    => "read from",
+            Access::Write   => "write to",
+            Access::Execute => "instruction fetch from",
+        };
+        let cause = if self.is_present() { "protection violation" } else { "non-present page" };
+
+        write!(f, "{} {} {:#x} ({})", mode, access, self.address.0, cause)?;
+        if self.is_reserved() {
+            write!(f, ", reserved bit set")?;
+        }
+        Ok(())
+    }
+}
@@ -1,15 +1,168 @@
-use core::ptr;
+// PHYSICAL FRAME ALLOCATOR
+//
+// One bitmap per region of free memory (bit set: frame in use). Each region
+// keeps its descriptor, bitmap and share counts in its own first frames,
+// regions are chained in address order: any number of regions can be tracked
+// without a heap.
+//
+// A frame mapped by several address spaces (copy-on-write) is freed when its
+// last owner lets it go.
+//
+// Frames holding the kernel image, the active page tables and the boot info
+// are reserved whatever the memory map says.
+
+use core::{fmt, mem, ptr};
+use core::marker::PhantomData;
 use bootloader::BootInfo;
-use bootloader::bootinfo::MemoryRegionType::Usable;
+use bootloader::bootinfo::MemoryRegionType;
+use crate::cpu;
+use crate::paging::{self, PageTable, PhysicalAddress, VirtualAddress, PHYSICAL_MEMORY_OFFSET, PAGE_SIZE_2M, PAGE_SIZE_1G};
+use crate::sync::IrqSpinlock;
+
+pub const FRAME_SIZE: u64 = 4096;
 
-pub static mut gFRAME_ALLOCATOR: FrameAllocator = FrameAllocator::new();
+pub static gFRAME_ALLOCATOR: IrqSpinlock<FrameAllocator> = IrqSpinlock::new(FrameAllocator::new());
 
-#[derive(Debug, Clone, Copy)]
-struct FrameRange(u64, u64);
+static mut BOOT_INFO: Option<&'static BootInfo> = None;
+
+// Lives at the start of the region it describes, followed by its bitmap and share counts
+struct Region {
+    next:   *mut Region,
+    start:  u64,        // first frame number
+    frames: u64,
+    free:   u64,
+    hint:   u64,        // bitmap word to start searching from
+    bitmap: *mut u64,   // virtual address, through the physical memory window
+    shares: *mut u16,   // owners beyond the first, per frame
+}
 
-#[derive(Debug)]
 pub struct FrameAllocator {
-    ranges : [FrameRange; 16]
+    regions: *mut Region,   // sorted by address
+}
+
+// The regions are only reached through the allocator lock
+unsafe impl Send for FrameAllocator {}
+
+// Borrow the allocator: regions are only reached through its lock guard
+struct Regions<'a> {
+    current:   *mut Region,
+    allocator: PhantomData<&'a FrameAllocator>,
+}
+
+struct RegionsMut<'a> {
+    current:   *mut Region,
+    allocator: PhantomData<&'a mut FrameAllocator>,
+}
+
+impl<'a> Iterator for Regions<'a> {
+    type Item = &'a Region;
+
+    fn next(&mut self) -> Option<&'a Region> {
+        let region = unsafe { self.current.as_ref()? };
+        self.current = region.next;
+        Some(region)
+    }
+}
+
+impl<'a> Iterator for RegionsMut<'a> {
+    type Item = &'a mut Region;
+
+    fn next(&mut self) -> Option<&'a mut Region> {
+        let region = unsafe { self.current.as_mut()? };     // each region handed out once
+        self.current = region.next;
+        Some(region)
+    }
+}
+
+impl Region {
+
+    fn end(&self) -> u64 {
+        self.start + self.frames
+    }
+
+    fn contains(&self, frame: u64) -> bool {
+        frame >= self.start && frame < self.end()
+    }
+
+    fn is_used(&self, index: u64) -> bool {
+        unsafe { *self.bitmap.add((index / 64) as usize) & (1 << (index % 64)) != 0 }
+    }
+
+    fn set_used(&mut self, index: u64, used: bool) {
+        unsafe {
+            let word = self.bitmap.add((index / 64) as usize);
+            match used {
+                true  => *word |= 1 << (index % 64),
+                false => *word &= !(1 << (index % 64)),
+            }
+        }
+        match used {
+            true  => self.free -= 1,
+            false => self.free += 1,
+        }
+    }
+
+    //============================================================
+    /// Mark the frames of [start, end) inside this region as used
+    //
+    //============================================================
+    fn reserve(&mut self, start: u64, end: u64) {
+        for frame in start.max(self.start)..end.min(self.end()) {
+            if !self.is_used(frame - self.start) {
+                self.set_used(frame - self.start, true);
+            }
+        }
+    }
+
+    //============================================================
+    //
+    //
+    //============================================================
+    fn allocate(&mut self) -> Option<u64> {
+
+        let words = (self.frames + 63) / 64;
+
+        for i in 0..words {
+            let word = (self.hint + i) % words;
+            let bits = unsafe { *self.bitmap.add(word as usize) };
+
+            if bits != !0 {
+                let index = word * 64 + (!bits).trailing_zeros() as u64;
+                if index >= self.frames {
+                    continue;       // padding bits of the last word are kept set
+                }
+                self.set_used(index, true);
+                self.hint = word;
+                return Some(self.start + index);
+            }
+        }
+        None
+    }
+
+    //============================================================
+    /// First fit run of `count` free frames starting on a multiple of `align` frames
+    //
+    //============================================================
+    fn allocate_run(&mut self, count: u64, align: u64) -> Option<u64> {
+
+        let mut index = ((self.start + align - 1) & !(align - 1)) - self.start;
+
+        while index + count <= self.frames {
+            match (index..index + count).rev().find(|&i| self.is_used(i)) {
+                Some(used) => {
+                    // restart past the used frame, on the next aligned frame
+                    index = ((self.start + used + 1 + align - 1) & !(align - 1)) - self.start;
+                }
+                None => {
+                    for i in index..index + count {
+                        self.set_used(i, true);
+                    }
+                    return Some(self.start + index);
+                }
+            }
+        }
+        None
+    }
 }
 
 impl FrameAllocator {
@@ -19,42 +172,406 @@ impl FrameAllocator {
     //
     //============================================================
     const fn new() -> FrameAllocator {
-        FrameAllocator {
-            ranges: [FrameRange(0, 0); 16]
+        FrameAllocator { regions: ptr::null_mut() }
+    }
+
+    //============================================================
+    /// Manage the usable memory of the boot memory map
+    //
+    //============================================================
+    pub fn init(info: &'static BootInfo) {
+
+        unsafe { BOOT_INFO = Some(info); }
+
+        print_memory_map(info);
+
+        let mut allocator = gFRAME_ALLOCATOR.lock();
+
+        for region in info.memory_map.iter().filter(|o| o.region_type == MemoryRegionType::Usable) {
+            allocator.add_usable(region.range.start_frame_number, region.range.end_frame_number);
+        }
+
+        crate::println!("Frame allocator: {} regions, {} free of {}",
+            allocator.regions().count(), Size(allocator.free() * FRAME_SIZE), Size(allocator.total() * FRAME_SIZE));
+    }
+
+    //============================================================
+    /// Hand over memory found after boot (hotplug, reclaimed firmware memory).
+    /// Partial frames at both ends are left out.
+    //============================================================
+    pub fn add_region(start: PhysicalAddress, end: PhysicalAddress) -> bool {
+        let start = (start.0 + FRAME_SIZE - 1) / FRAME_SIZE;
+        let end   = end.0 / FRAME_SIZE;
+        gFRAME_ALLOCATOR.lock().add_frames(start, end)
+    }
+
+    //============================================================
+    /// Give the ACPI tables memory to the allocator, once they have been parsed
+    //
+    //============================================================
+    pub fn reclaim_acpi_memory() {
+
+        let info = match unsafe { BOOT_INFO } {
+            Some(info) => info,
+            None       => return,
+        };
+
+        let mut reclaimed = 0;
+        for region in info.memory_map.iter().filter(|o| o.region_type == MemoryRegionType::AcpiReclaimable) {
+            if gFRAME_ALLOCATOR.lock().add_frames(region.range.start_frame_number, region.range.end_frame_number) {
+                reclaimed += region.range.end_addr() - region.range.start_addr();
+            }
+        }
+
+        if reclaimed != 0 {
+            crate::println!("Reclaimed {} of ACPI memory", Size(reclaimed));
+        }
+    }
+
+    // Usable boot region, minus whatever another memory map entry claims (overlapping maps)
+    fn add_usable(&mut self, start: u64, end: u64) {
+
+        if start >= end {
+            return;
+        }
+
+        let info = unsafe { BOOT_INFO.unwrap() };
+        let overlap = info.memory_map.iter().find(|o| o.region_type != MemoryRegionType::Usable
+            && o.range.start_frame_number < end && o.range.end_frame_number > start);
+
+        match overlap {
+            Some(other) => {
+                self.add_usable(start, other.range.start_frame_number.min(end));
+                self.add_usable(other.range.end_frame_number.max(start), end);
+            }
+            None => { self.add_frames(start, end); }
+        }
+    }
+
+    //============================================================
+    /// Track frames [start, end): the descriptor and bitmap take the first
+    /// unreserved frames of the range
+    //============================================================
+    fn add_frames(&mut self, start: u64, end: u64) -> bool {
+
+        if let Some(region) = self.regions().find(|r| r.start < end && r.end() > start) {
+            crate::println!("Frame allocator: {:#x}-{:#x} overlaps {:#x}-{:#x}, ignored",
+                start * FRAME_SIZE, end * FRAME_SIZE, region.start * FRAME_SIZE, region.end() * FRAME_SIZE);
+            return false;
+        }
+
+        let frames = end.saturating_sub(start);
+        let words  = (frames + 63) / 64;
+        let meta   = (mem::size_of::<Region>() as u64 + words * 8 + frames * 2 + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        // first run of `meta` frames clear of every reservation
+        let mut first = start;
+        loop {
+            if first + meta >= end {
+                return false;       // too small to be worth it
+            }
+
+            let mut blocked = None;
+            for_each_reserved(&mut |s, e| {
+                if s < first + meta && e > first {
+                    blocked = Some(blocked.unwrap_or(0).max(e));
+                }
+            });
+
+            match blocked {
+                Some(e) => first = e,
+                None    => break,
+            }
         }
+
+        let header = (PHYSICAL_MEMORY_OFFSET + first * FRAME_SIZE) as *mut Region;
+        let bitmap = unsafe { header.add(1) } as *mut u64;
+        let shares = unsafe { bitmap.add(words as usize) } as *mut u16;
+
+        unsafe {
+            ptr::write_bytes(bitmap, 0, words as usize);
+            ptr::write_bytes(shares, 0, frames as usize);
+            if frames % 64 != 0 {
+                *bitmap.add(words as usize - 1) = !0 << (frames % 64);
+            }
+            ptr::write(header, Region { next: ptr::null_mut(), start, frames, free: frames, hint: 0, bitmap, shares });
+        }
+
+        let region = unsafe { &mut *header };
+        region.reserve(first, first + meta);
+        for_each_reserved(&mut |s, e| region.reserve(s, e));
+
+        // keep the chain sorted
+        let mut link = &mut self.regions;
+        while let Some(next) = unsafe { link.as_mut() } {
+            if next.start > start {
+                break;
+            }
+            link = &mut next.next;
+        }
+        region.next = *link;
+        *link = header;
+
+        true
+    }
+
+    //============================================================
+    /// One zeroed frame, returns its physical address
+    //
+    //============================================================
+    pub fn allocate_frame() -> Option<u64> {
+
+        let frame = gFRAME_ALLOCATOR.lock().regions_mut().find_map(|region| region.allocate())?;
+
+        let address = frame * FRAME_SIZE;
+        unsafe { ptr::write_bytes((PHYSICAL_MEMORY_OFFSET + address) as *mut u64, 0, 4096>>3); }
+        Some(address)
+    }
+
+    //============================================================
+    /// `count` physically contiguous zeroed frames, the first one aligned to
+    /// `align` bytes (power of two, at least a frame)
+    //============================================================
+    pub fn allocate_frames(count: u64, align: u64) -> Option<u64> {
+
+        assert!(align.is_power_of_two() && align >= FRAME_SIZE, "invalid frame alignment {:#x}", align);
+
+        if count == 0 {
+            return None;
+        }
+
+        let frame = gFRAME_ALLOCATOR.lock().regions_mut()
+            .filter(|region| region.free >= count)
+            .find_map(|region| region.allocate_run(count, align / FRAME_SIZE))?;
+
+        let address = frame * FRAME_SIZE;
+        unsafe { ptr::write_bytes((PHYSICAL_MEMORY_OFFSET + address) as *mut u8, 0, (count * FRAME_SIZE) as usize); }
+        Some(address)
     }
 
     //============================================================
     //
     //
     //============================================================
-    pub fn init(info: &BootInfo) {
+    pub fn deallocate_frame(address: u64) {
+        Self::deallocate_frames(address, 1);
+    }
 
-        let regions = info.memory_map.iter().filter(|o| o.region_type==Usable);
-        let mut ranges = unsafe { gFRAME_ALLOCATOR.ranges.iter_mut() };
+    //============================================================
+    /// Give back frames from `allocate_frame` or `allocate_frames`.
+    /// Shared frames only lose an owner.
+    //============================================================
+    pub fn deallocate_frames(address: u64, count: u64) {
+
+        assert!(address % FRAME_SIZE == 0, "unaligned frame {:#x}", address);
+
+        let mut allocator = gFRAME_ALLOCATOR.lock();
+
+        for frame in address / FRAME_SIZE..address / FRAME_SIZE + count {
+            let (region, index) = allocator.used_frame(frame);
+
+            let shares = unsafe { &mut *region.shares.add(index as usize) };
+            if *shares > 0 {
+                *shares -= 1;
+                continue;
+            }
 
-        for (region, mut range) in regions.zip(ranges) {
-            range.0 = region.range.start_addr() >> 12;
-            range.1 = region.range.end_addr() >> 12;
+            region.set_used(index, false);
+            region.hint = index / 64;
         }
+    }
+
+    //============================================================
+    /// One more owner for an allocated frame
+    //
+    //============================================================
+    pub fn share_frame(address: u64) {
+
+        let mut allocator = gFRAME_ALLOCATOR.lock();
+        let (region, index) = allocator.used_frame(address / FRAME_SIZE);
 
-        unsafe { crate::println!("{:#x?}", gFRAME_ALLOCATOR); }
+        let shares = unsafe { &mut *region.shares.add(index as usize) };
+        *shares = shares.checked_add(1).expect("frame shared too many times");
+    }
+
+    //============================================================
+    /// Owners of a frame, 0 when free
+    //
+    //============================================================
+    pub fn reference_count(address: u64) -> u32 {
+
+        let allocator = gFRAME_ALLOCATOR.lock();
+        let frame = address / FRAME_SIZE;
+
+        match allocator.regions().find(|region| region.contains(frame)) {
+            Some(region) if region.is_used(frame - region.start) =>
+                1 + unsafe { *region.shares.add((frame - region.start) as usize) } as u32,
+            _ => 0,
+        }
+    }
+
+    //============================================================
+    /// True if [start, end) has RAM: frames we manage or anything the memory
+    /// map does not call reserved (kernel image, page tables, boot info...)
+    //============================================================
+    pub fn overlaps_ram(start: u64, end: u64) -> bool {
+
+        let (first, last) = (start / FRAME_SIZE, end / FRAME_SIZE + (end % FRAME_SIZE != 0) as u64);
+        if gFRAME_ALLOCATOR.lock().regions().any(|region| region.start < last && region.end() > first) {
+            return true;
+        }
+
+        let info = match unsafe { BOOT_INFO } {
+            Some(info) => info,
+            None       => return true,
+        };
+        info.memory_map.iter().any(|region| region.region_type != MemoryRegionType::Reserved
+            && start < region.range.end_addr() && end > region.range.start_addr())
+    }
+
+    // Region and index of an allocated frame, panics on frames that are free or not ours
+    fn used_frame(&mut self, frame: u64) -> (&mut Region, u64) {
+
+        let region = self.regions_mut().find(|region| region.contains(frame))
+            .unwrap_or_else(|| panic!("frame {:#x} not managed by the frame allocator", frame * FRAME_SIZE));
+
+        let index = frame - region.start;
+        assert!(region.is_used(index), "frame {:#x} is free", frame * FRAME_SIZE);
+
+        (region, index)
     }
 
     //============================================================
     //
     //
     //============================================================
-    pub fn allocate_frame() -> Option<u64> {
+    pub fn free_frames() -> u64 {
+        gFRAME_ALLOCATOR.lock().free()
+    }
+
+    //============================================================
+    /// Frames handed out (reservations and the allocator's own metadata included)
+    //
+    //============================================================
+    pub fn used_frames() -> u64 {
+        let allocator = gFRAME_ALLOCATOR.lock();
+        allocator.total() - allocator.free()
+    }
+
+    fn regions(&self) -> Regions {
+        Regions { current: self.regions, allocator: PhantomData }
+    }
+
+    fn regions_mut(&mut self) -> RegionsMut {
+        RegionsMut { current: self.regions, allocator: PhantomData }
+    }
+
+    fn total(&self) -> u64 {
+        self.regions().map(|region| region.frames).sum()
+    }
+
+    fn free(&self) -> u64 {
+        self.regions().map(|region| region.free).sum()
+    }
+}
+
+//============================================================
+/// Report the frame ranges [start, end) nobody may allocate: kernel image,
+/// active page tables and boot info
+//============================================================
+fn for_each_reserved(f: &mut dyn FnMut(u64, u64)) {
+
+    // the kernel image is everything mapped in the PML4 slot of the kernel code
+    let kernel_slot = (for_each_reserved as usize >> 39) & 0x1ff;
+
+    let p4 = cpu::read_cr3();
+    f(p4 / FRAME_SIZE, p4 / FRAME_SIZE + 1);
 
-        for range in unsafe { gFRAME_ALLOCATOR.ranges.iter_mut() } {
-            if range.0 < range.1 {
-                let address = range.0 << 12;
-                unsafe { ptr::write_bytes((0x18000000000+address) as *mut u64, 0, 4096>>3); }
-                range.0 += 1;
-                return Some(address);
+    for (index4, entry4) in table(p4).entries.iter().enumerate() {
+        if !entry4.is_present() {
+            continue;
+        }
+        let kernel = index4 == kernel_slot;
+        let p3 = entry4.address().0;
+        f(p3 / FRAME_SIZE, p3 / FRAME_SIZE + 1);
+
+        for entry3 in table(p3).entries.iter().filter(|e| e.is_present()) {
+            let p2 = entry3.address().0;
+            if entry3.is_huge() {
+                let p2 = entry3.huge_address(PAGE_SIZE_1G).0;
+                if kernel { f(p2 / FRAME_SIZE, p2 / FRAME_SIZE + 512 * 512); }
+                continue;
+            }
+            f(p2 / FRAME_SIZE, p2 / FRAME_SIZE + 1);
+
+            for entry2 in table(p2).entries.iter().filter(|e| e.is_present()) {
+                let p1 = entry2.address().0;
+                if entry2.is_huge() {
+                    let p1 = entry2.huge_address(PAGE_SIZE_2M).0;
+                    if kernel { f(p1 / FRAME_SIZE, p1 / FRAME_SIZE + 512); }
+                    continue;
+                }
+                f(p1 / FRAME_SIZE, p1 / FRAME_SIZE + 1);
+
+                if kernel {
+                    for entry1 in table(p1).entries.iter().filter(|e| e.is_present()) {
+                        let frame = entry1.address().0 / FRAME_SIZE;
+                        f(frame, frame + 1);
+                    }
+                }
             }
         }
-        None
+    }
+
+    if let Some(info) = unsafe { BOOT_INFO } {
+        let start = info as *const BootInfo as u64;
+        let end   = start + mem::size_of::<BootInfo>() as u64;
+
+        for page in (start & !(FRAME_SIZE - 1)..end).step_by(FRAME_SIZE as usize) {
+            if let Some(frame) = paging::translate_addr(VirtualAddress(page)) {
+                f(frame.0 / FRAME_SIZE, frame.0 / FRAME_SIZE + 1);
+            }
+        }
+    }
+}
+
+fn table(address: u64) -> &'static PageTable {
+    unsafe { &*((PHYSICAL_MEMORY_OFFSET + address) as *const PageTable) }
+}
+
+//============================================================
+/// One line per run of same-type entries of the boot memory map
+//
+//============================================================
+fn print_memory_map(info: &BootInfo) {
+
+    let mut regions = info.memory_map.iter().peekable();
+
+    while let Some(first) = regions.next() {
+        let mut end = first.range.end_addr();
+        while let Some(next) = regions.peek() {
+            if next.region_type != first.region_type || next.range.start_addr() != end {
+                break;
+            }
+            end = next.range.end_addr();
+            regions.next();
+        }
+
+        crate::println!("  {:#012x}-{:#012x} {:?} ({})",
+            first.range.start_addr(), end, first.region_type, Size(end - first.range.start_addr()));
+    }
+}
+
+// Byte count in the largest unit that fits (rounded down)
+struct Size(u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            n if n >= 1 << 30 => write!(f, "{} GiB", n >> 30),
+            n if n >= 1 << 20 => write!(f, "{} MiB", n >> 20),
+            n if n >= 1 << 10 => write!(f, "{} KiB", n >> 10),
+            n                 => write!(f, "{} B", n),
+        }
     }
 }
@@ -1,4 +1,9 @@
 
 mod frame_allocator;
+pub mod context;
+pub mod fault;
+pub mod vma;
+pub mod selftest;
 
 pub use frame_allocator::FrameAllocator;
+pub use context::{Context, USER_SPACE_START, USER_SPACE_END};
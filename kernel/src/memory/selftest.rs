@@ -0,0 +1,52 @@
+use core::ptr;
+use crate::paging::{PageTableFlags, PAGE_SIZE_4K, PHYSICAL_MEMORY_OFFSET};
+use super::Context;
+use super::fault::Access;
+use super::vma::{Backing, Protection};
+
+// Regression checks run at boot, the kernel has no test harness
+
+const PATTERN: u64 = 0x5EED_F00D_5EED_F00D;
+
+//============================================================
+/// A PROT_NONE page faults on any access (not present, refused by its
+/// area) and gets its frame back when its rights are restored
+//============================================================
+pub fn run() {
+
+    let mut context = Context::new().expect("memory self-test: no frame for a context");
+    let start = context.mmap(None, 2 * PAGE_SIZE_4K, Protection::READ | Protection::WRITE, Backing::Anonymous)
+        .expect("memory self-test: mmap failed");
+
+    assert!(context.allocate(start, PageTableFlags::WRITABLE), "memory self-test: no frame for a page");
+    let (frame, _) = leaf(&mut context, start);
+    unsafe { ptr::write((PHYSICAL_MEMORY_OFFSET + frame) as *mut u64, PATTERN); }
+
+    context.mprotect(start, 2 * PAGE_SIZE_4K, Protection::empty()).expect("memory self-test: mprotect failed");
+
+    let (address, flags) = leaf(&mut context, start);
+    assert!(!flags.contains(PageTableFlags::PRESENT), "memory self-test: PROT_NONE page still present");
+    assert!(address == frame, "memory self-test: PROT_NONE page lost its frame");
+
+    let vma = context.vmas().find(start).unwrap();
+    for &access in [Access::Read, Access::Write, Access::Execute].iter() {
+        assert!(!vma.allows(access), "memory self-test: PROT_NONE area allows {:?}", access);
+    }
+
+    context.mprotect(start, 2 * PAGE_SIZE_4K, Protection::READ).expect("memory self-test: mprotect failed");
+
+    let (address, flags) = leaf(&mut context, start);
+    assert!(flags.contains(PageTableFlags::PRESENT) && address == frame, "memory self-test: page not restored");
+    assert!(!flags.contains(PageTableFlags::WRITABLE), "memory self-test: read-only page writable");
+    assert!(unsafe { ptr::read((PHYSICAL_MEMORY_OFFSET + frame) as *const u64) } == PATTERN, "memory self-test: contents lost");
+
+    drop(context);
+    crate::println!("Memory self-test passed");
+}
+
+// Frame and flags of the leaf entry of a page
+fn leaf(context: &mut Context, page: u64) -> (u64, PageTableFlags) {
+    let mut mapper = context.mapper();
+    let entry = mapper.entry_mut(page).expect("memory self-test: page has no table");
+    (entry.address().0, entry.flags())
+}
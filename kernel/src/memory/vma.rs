@@ -0,0 +1,392 @@
+// VIRTUAL MEMORY AREAS
+//
+// What a user address space is allowed to contain, independently of what is
+// mapped yet: pages are populated on the first access (see `resolve_fault`).
+// Areas never overlap and are kept in a tree ordered by start address.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::fmt;
+use crate::paging::{PageTableFlags, PAGE_SIZE_4K};
+use crate::process;
+use crate::sync::IrqSpinlock;
+use super::context::{DEVICE, SHARED};
+use super::fault::{Access, PageFault, Resolution};
+use super::{FrameAllocator, USER_SPACE_START, USER_SPACE_END};
+
+/// Unmapped pages left between areas placed by `find_free`
+pub const GUARD_GAP: u64 = PAGE_SIZE_4K;
+
+/// Where `find_free` starts looking, clear of the program image
+pub const MMAP_BASE: u64 = 0x0000_0010_0000_0000;
+
+bitflags! {
+    /// Access rights of an area
+    pub struct Protection: u64 {
+        const READ    = 1 << 0;
+        const WRITE   = 1 << 1;
+        const EXECUTE = 1 << 2;
+    }
+}
+
+#[derive(Clone)]
+pub enum Backing {
+    Anonymous,                                      // zeroed private pages, copy-on-write across fork
+    Physical(u64),                                  // device memory, physical address of the area start
+    Shared { object: Arc<SharedMemory>, offset: u64 },  // same frames in every context mapping it
+}
+
+#[derive(Clone)]
+pub struct Vma {
+    pub start:      u64,
+    pub end:        u64,
+    pub protection: Protection,
+    pub backing:    Backing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VmaError {
+    InvalidRange,       // unaligned, empty or outside user space
+    Overlap,            // the range is already (partly) in use
+    NoSpace,            // no hole big enough
+}
+
+//============================================================
+/// Anonymous memory shared by every context mapping it (survives fork as is)
+//
+//============================================================
+pub struct SharedMemory {
+    pages:  u64,
+    frames: IrqSpinlock<BTreeMap<u64, u64>>,    // populated pages only
+}
+
+#[derive(Clone)]
+pub struct VmaTree {
+    areas: BTreeMap<u64, Vma>,      // by start address
+}
+
+impl SharedMemory {
+
+    //============================================================
+    //
+    //
+    //============================================================
+    pub fn new(size: u64) -> Arc<SharedMemory> {
+        let pages = size / PAGE_SIZE_4K + (size % PAGE_SIZE_4K != 0) as u64;
+        Arc::new(SharedMemory { pages, frames: IrqSpinlock::new(BTreeMap::new()) })
+    }
+
+    //============================================================
+    /// Frame of a page of the object, allocated on first use
+    //
+    //============================================================
+    pub fn frame(&self, page: u64) -> Option<u64> {
+
+        if page >= self.pages {
+            return None;
+        }
+
+        let mut frames = self.frames.lock();
+        match frames.get(&page) {
+            Some(&frame) => Some(frame),
+            None => {
+                let frame = FrameAllocator::allocate_frame()?;
+                frames.insert(page, frame);
+                Some(frame)
+            }
+        }
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        for &frame in self.frames.lock().values() {
+            FrameAllocator::deallocate_frame(frame);
+        }
+    }
+}
+
+impl Vma {
+
+    //============================================================
+    /// Leaf flags of the pages of this area
+    //
+    //============================================================
+    pub fn page_flags(&self) -> PageTableFlags {
+
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER;
+
+        if self.protection.contains(Protection::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.protection.contains(Protection::EXECUTE) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        match self.backing {
+            Backing::Anonymous     => {}
+            Backing::Physical(_)   => flags |= DEVICE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            Backing::Shared { .. } => flags |= SHARED,
+        }
+        flags
+    }
+
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read    => self.protection.intersects(Protection::READ | Protection::WRITE | Protection::EXECUTE),
+            Access::Write   => self.protection.contains(Protection::WRITE),
+            Access::Execute => self.protection.contains(Protection::EXECUTE),
+        }
+    }
+
+    // Keep [start, end) (inside the area), backing offsets follow
+    fn slice(&self, start: u64, end: u64) -> Vma {
+
+        let delta = start - self.start;
+        let backing = match &self.backing {
+            Backing::Anonymous                => Backing::Anonymous,
+            Backing::Physical(address)        => Backing::Physical(address + delta),
+            Backing::Shared { object, offset } => Backing::Shared { object: object.clone(), offset: offset + delta },
+        };
+
+        Vma { start, end, protection: self.protection, backing }
+    }
+}
+
+impl VmaTree {
+
+    //============================================================
+    //
+    //
+    //============================================================
+    pub fn new() -> VmaTree {
+        VmaTree { areas: BTreeMap::new() }
+    }
+
+    //============================================================
+    /// Area containing an address
+    //
+    //============================================================
+    pub fn find(&self, address: u64) -> Option<&Vma> {
+        self.areas.range(..=address).next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| address < vma.end)
+    }
+
+    //============================================================
+    /// Add an area, refused if it overlaps an existing one
+    //
+    //============================================================
+    pub fn insert(&mut self, vma: Vma) -> Result<(), VmaError> {
+
+        check_range(vma.start, vma.end)?;
+
+        if self.overlaps(vma.start, vma.end) {
+            return Err(VmaError::Overlap);
+        }
+
+        self.areas.insert(vma.start, vma);
+        Ok(())
+    }
+
+    //============================================================
+    /// Lowest hole of `size` bytes from MMAP_BASE, a guard gap away from its neighbours
+    //
+    //============================================================
+    pub fn find_free(&self, size: u64) -> Result<u64, VmaError> {
+
+        if size > USER_SPACE_END - MMAP_BASE {
+            return Err(VmaError::NoSpace);     // keeps the sums below from overflowing
+        }
+
+        let mut candidate = MMAP_BASE;
+
+        for vma in self.areas.values().filter(|vma| vma.end + GUARD_GAP > MMAP_BASE) {
+            if candidate + size + GUARD_GAP <= vma.start {
+                break;
+            }
+            candidate = candidate.max(vma.end + GUARD_GAP);
+        }
+
+        match candidate.checked_add(size) {
+            Some(end) if end <= USER_SPACE_END => Ok(candidate),
+            _ => Err(VmaError::NoSpace),
+        }
+    }
+
+    //============================================================
+    /// Take [start, end) out of the tree, splitting the areas at the edges.
+    /// Returns the removed pieces.
+    //============================================================
+    pub fn remove(&mut self, start: u64, end: u64) -> Result<Vec<Vma>, VmaError> {
+
+        check_range(start, end)?;
+
+        let mut removed = Vec::new();
+
+        for vma in self.take_overlapping(start, end) {
+            if vma.start < start {
+                self.areas.insert(vma.start, vma.slice(vma.start, start));
+            }
+            if vma.end > end {
+                self.areas.insert(end, vma.slice(end, vma.end));
+            }
+            removed.push(vma.slice(vma.start.max(start), vma.end.min(end)));
+        }
+        Ok(removed)
+    }
+
+    //============================================================
+    /// Change the rights of [start, end), which must be fully covered.
+    /// Returns the updated pieces.
+    //============================================================
+    pub fn protect(&mut self, start: u64, end: u64, protection: Protection) -> Result<Vec<Vma>, VmaError> {
+
+        check_range(start, end)?;
+
+        if !self.covers(start, end) {
+            return Err(VmaError::InvalidRange);
+        }
+
+        let mut updated = Vec::new();
+
+        for vma in self.remove(start, end)? {
+            let vma = Vma { protection, ..vma };
+            updated.push(vma.clone());
+            self.areas.insert(vma.start, vma);
+        }
+        Ok(updated)
+    }
+
+    //============================================================
+    /// True if every byte of [start, end) is in an area
+    //
+    //============================================================
+    pub fn covers(&self, start: u64, end: u64) -> bool {
+
+        let mut address = start;
+        while address < end {
+            match self.find(address) {
+                Some(vma) => address = vma.end,
+                None      => return false,
+            }
+        }
+        true
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+
+    //============================================================
+    /// One line per area
+    //
+    //============================================================
+    pub fn dump(&self) {
+        crate::println!("{} area(s):", self.areas.len());
+        for vma in self.areas.values() {
+            crate::println!("  {:?}", vma);
+        }
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.areas.range(..end).next_back().map_or(false, |(_, vma)| vma.end > start)
+    }
+
+    fn take_overlapping(&mut self, start: u64, end: u64) -> Vec<Vma> {
+
+        let mut keys: Vec<u64> = self.areas.range(start..end).map(|(&key, _)| key).collect();
+        if let Some(vma) = self.find(start) {
+            if vma.start < start {
+                keys.push(vma.start);
+            }
+        }
+        keys.iter().filter_map(|key| self.areas.remove(key)).collect()
+    }
+}
+
+//============================================================
+/// Populate a page of an area of the current process on first access
+//
+//============================================================
+pub fn resolve_fault(fault: &PageFault) -> Resolution {
+
+    let address = fault.address.0;
+    if address < USER_SPACE_START || address >= USER_SPACE_END {
+        return Resolution::Unhandled;
+    }
+
+    let resolution = process::with_current(|context| {
+
+        let vma = match context.vmas().find(address) {
+            Some(vma) => vma.clone(),
+            None      => return Resolution::Unhandled,
+        };
+
+        if !vma.allows(fault.access()) {
+            return Resolution::TerminateProcess;
+        }
+        if fault.is_present() {
+            return Resolution::Unhandled;   // allowed but present: not an area problem
+        }
+
+        let page = fault.page();
+        let frame = match &vma.backing {
+            Backing::Anonymous => FrameAllocator::allocate_frame(),
+            Backing::Physical(address) => Some(address + (page - vma.start)),
+            Backing::Shared { object, offset } => object.frame((offset + page - vma.start) / PAGE_SIZE_4K)
+                .map(|frame| { FrameAllocator::share_frame(frame); frame }),
+        };
+
+        let frame = match frame {
+            Some(frame) => frame,
+            None        => return Resolution::TerminateProcess,     // out of memory
+        };
+
+        match context.mapper().map_to(page, frame, vma.page_flags()) {
+            Ok(()) => Resolution::Resolved,
+            Err(_) => {
+                if !matches!(vma.backing, Backing::Physical(_)) {
+                    FrameAllocator::deallocate_frame(frame);    // new, or the share taken above
+                }
+                Resolution::TerminateProcess    // no frame for a page table
+            }
+        }
+    });
+
+    match resolution {
+        Some(Resolution::TerminateProcess) if !fault.is_user() => Resolution::Unhandled,
+        Some(resolution) => resolution,
+        None             => Resolution::Unhandled,
+    }
+}
+
+fn check_range(start: u64, end: u64) -> Result<(), VmaError> {
+    if start % PAGE_SIZE_4K != 0 || end % PAGE_SIZE_4K != 0 || start >= end
+        || start < USER_SPACE_START || end > USER_SPACE_END {
+        return Err(VmaError::InvalidRange);
+    }
+    Ok(())
+}
+
+impl fmt::Debug for Vma {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rights = [(Protection::READ, 'r'), (Protection::WRITE, 'w'), (Protection::EXECUTE, 'x')];
+        write!(f, "{:#014x}-{:#014x} ", self.start, self.end)?;
+        for &(right, letter) in rights.iter() {
+            write!(f, "{}", if self.protection.contains(right) { letter } else { '-' })?;
+        }
+        match &self.backing {
+            Backing::Anonymous                 => write!(f, " anonymous"),
+            Backing::Physical(address)         => write!(f, " physical {:#x}", address),
+            Backing::Shared { object, offset } => write!(f, " shared {:p}+{:#x}", Arc::as_ptr(object), offset),
+        }
+    }
+}
+
+impl fmt::Debug for VmaTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.areas.values()).finish()
+    }
+}
@@ -1,55 +1,193 @@
 use core::ptr::NonNull;
+use crate::cpu;
 use crate::memory::FrameAllocator;
-use super::{PageTable, PageTableEntry};
+use super::{tlb, PageTable, PageTableEntry, PageTableFlags, PhysicalAddress, VirtualAddress, PHYSICAL_MEMORY_OFFSET};
+use super::{PAGE_SIZE_4K, PAGE_SIZE_2M, PAGE_SIZE_1G};
 
-//
+// Edits the tables of one address space, reached through the physical memory window
 pub struct Mapper {
     p4: NonNull<PageTable>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MapError {
+    NoTableFrame,       // no frame left for a missing page table
+}
+
 impl Mapper {
 
+    //============================================================
+    /// Mapper of the active address space (CR3)
+    //
+    //============================================================
     pub fn new() -> Mapper {
+        unsafe { Mapper::for_table(PhysicalAddress(cpu::read_cr3())) }
+    }
+
+    //============================================================
+    /// Mapper of the address space rooted at the PML4 frame `p4`.
+    /// The caller guarantees the frame holds a PML4.
+    //============================================================
+    pub unsafe fn for_table(p4: PhysicalAddress) -> Mapper {
         Mapper {
-            p4: unsafe { NonNull::new_unchecked(0x18000001000 as *mut PageTable) },
+            p4: NonNull::new_unchecked((PHYSICAL_MEMORY_OFFSET + p4.0) as *mut PageTable),
         }
     }
 
     //============================================================
-    /// Map a page to a frame
+    /// Map a page to a frame (a previous mapping is replaced and invalidated)
     //
     //============================================================
-    pub fn map_to(&mut self, page: u64, frame: u64, flags: u64) {
+    pub fn map_to(&mut self, page: u64, frame: u64, flags: PageTableFlags) -> Result<(), MapError> {
 
         let index4 = ((page & 0x0000FF8000000000) >> 39) as usize;
         let index3 = ((page & 0x0000007FC0000000) >> 30) as usize;
         let index2 = ((page & 0x000000003FE00000) >> 21) as usize;
         let index1 = ((page & 0x00000000001FF000) >> 12) as usize;
 
-        let p3 = Self::get_or_create(unsafe{self.p4.as_mut()}, index4);
-        let p2 = Self::get_or_create(p3, index3);
-        let p1 = Self::get_or_create(p2, index2);
+        let p3 = Self::get_or_create(unsafe{self.p4.as_mut()}, index4, flags)?;
+        let p2 = Self::get_or_create(p3, index3, flags)?;
+        let p1 = Self::get_or_create(p2, index2, flags)?;
 
-        // assert!(is_unused);
+        let previous = p1.entries[index1].is_present();
+        p1.entries[index1].set(PhysicalAddress(frame), flags);
 
-        p1.entries[index1].entry = frame | flags;
+        if previous {
+            tlb::shootdown(VirtualAddress(page));
+        }
+        Ok(())
     }
 
     //============================================================
+    /// Map a 2 MiB page (both addresses 2 MiB aligned)
     //
+    //============================================================
+    pub fn map_huge_2m(&mut self, page: u64, frame: u64, flags: PageTableFlags) -> Result<(), MapError> {
+
+        assert!(page % PAGE_SIZE_2M == 0 && frame % PAGE_SIZE_2M == 0, "unaligned 2 MiB page {:#x} -> {:#x}", page, frame);
+
+        let index4 = ((page & 0x0000FF8000000000) >> 39) as usize;
+        let index3 = ((page & 0x0000007FC0000000) >> 30) as usize;
+        let index2 = ((page & 0x000000003FE00000) >> 21) as usize;
+
+        let p3 = Self::get_or_create(unsafe{self.p4.as_mut()}, index4, flags)?;
+        let p2 = Self::get_or_create(p3, index3, flags)?;
+
+        Self::set_huge(&mut p2.entries[index2], page, frame, flags);
+        Ok(())
+    }
+
+    //============================================================
+    /// Map a 1 GiB page (both addresses 1 GiB aligned, see `supports_1g_pages`)
     //
     //============================================================
-    fn get_or_create(page: &mut PageTable, index: usize) -> &mut PageTable {
+    pub fn map_huge_1g(&mut self, page: u64, frame: u64, flags: PageTableFlags) -> Result<(), MapError> {
 
-        if page.entries[index].entry == 0 {
+        assert!(super::supports_1g_pages(), "1 GiB pages not supported by this CPU");
+        assert!(page % PAGE_SIZE_1G == 0 && frame % PAGE_SIZE_1G == 0, "unaligned 1 GiB page {:#x} -> {:#x}", page, frame);
 
-            let frame = FrameAllocator::allocate_frame().unwrap();  // todo: handle no frame available
+        let index4 = ((page & 0x0000FF8000000000) >> 39) as usize;
+        let index3 = ((page & 0x0000007FC0000000) >> 30) as usize;
+
+        let p3 = Self::get_or_create(unsafe{self.p4.as_mut()}, index4, flags)?;
+
+        Self::set_huge(&mut p3.entries[index3], page, frame, flags);
+        Ok(())
+    }
 
-            page.entries[index].entry = frame | 1 | 2 | 4;  // PRESENT | WRITABLE | USER_ACCESSIBLE;
+    //============================================================
+    /// Map `size` bytes, each step with the largest page both addresses
+    /// are aligned for. On failure what was mapped so far stays mapped.
+    //============================================================
+    pub fn map_range(&mut self, page: u64, frame: u64, size: u64, flags: PageTableFlags) -> Result<(), MapError> {
+
+        assert!(page % PAGE_SIZE_4K == 0 && frame % PAGE_SIZE_4K == 0, "unaligned range {:#x} -> {:#x}", page, frame);
+
+        let gigabyte_pages = super::supports_1g_pages();
+        let end = page + size;
+        let mut offset = 0;
+
+        while page + offset < end {
+            let (virt, phys, left) = (page + offset, frame + offset, end - (page + offset));
+            let fits = |size: u64| virt % size == 0 && phys % size == 0 && left >= size;
+
+            offset += if gigabyte_pages && fits(PAGE_SIZE_1G) {
+                self.map_huge_1g(virt, phys, flags)?;
+                PAGE_SIZE_1G
+            } else if fits(PAGE_SIZE_2M) {
+                self.map_huge_2m(virt, phys, flags)?;
+                PAGE_SIZE_2M
+            } else {
+                self.map_to(virt, phys, flags)?;
+                PAGE_SIZE_4K
+            };
         }
+        Ok(())
+    }
 
-        let frame = page.entries[index].entry & 0x000fffff_fffff000;
+    //============================================================
+    /// Leaf (4 KiB) entry of a page, None if a table is missing
+    //
+    //============================================================
+    pub fn entry_mut(&mut self, page: u64) -> Option<&mut PageTableEntry> {
+
+        let index4 = ((page & 0x0000FF8000000000) >> 39) as usize;
+        let index3 = ((page & 0x0000007FC0000000) >> 30) as usize;
+        let index2 = ((page & 0x000000003FE00000) >> 21) as usize;
+        let index1 = ((page & 0x00000000001FF000) >> 12) as usize;
+
+        let p3 = Self::next_table(unsafe{self.p4.as_mut()}, index4)?;
+        let p2 = Self::next_table(p3, index3)?;
+        let p1 = Self::next_table(p2, index2)?;
+
+        Some(&mut p1.entries[index1])
+    }
+
+    //============================================================
+    //
+    //
+    //============================================================
+    fn next_table(page: &mut PageTable, index: usize) -> Option<&mut PageTable> {
+
+        let entry = &page.entries[index];
+        if !entry.is_present() || entry.is_huge() {
+            return None;
+        }
 
-        unsafe { &mut *((0x18000000000 + frame) as *mut PageTable) }
+        Some(unsafe { &mut *((PHYSICAL_MEMORY_OFFSET + entry.address().0) as *mut PageTable) })
+    }
+
+    //============================================================
+    /// Next level table, created if missing. Intermediate entries only get
+    /// USER when a user page is mapped below them.
+    //============================================================
+    fn get_or_create(page: &mut PageTable, index: usize, flags: PageTableFlags) -> Result<&mut PageTable, MapError> {
+
+        let entry = &mut page.entries[index];
+        assert!(!entry.is_huge(), "mapping a page inside a huge page");
+
+        if entry.is_unused() {
+            let frame = FrameAllocator::allocate_frame().ok_or(MapError::NoTableFrame)?;
+            entry.set(PhysicalAddress(frame), PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+
+        if flags.contains(PageTableFlags::USER) && !entry.flags().contains(PageTableFlags::USER) {
+            entry.set_flags(entry.flags() | PageTableFlags::USER);
+        }
+
+        Ok(unsafe { &mut *((PHYSICAL_MEMORY_OFFSET + entry.address().0) as *mut PageTable) })
+    }
+
+    // Leaf entry at the P2 or P3 level; a table already there would be leaked
+    fn set_huge(entry: &mut PageTableEntry, page: u64, frame: u64, flags: PageTableFlags) {
+
+        assert!(!entry.is_present() || entry.is_huge(), "huge page {:#x} over an existing page table", page);
+
+        let previous = entry.is_present();
+        entry.set(PhysicalAddress(frame), flags | PageTableFlags::HUGE);
+
+        if previous {
+            tlb::shootdown(VirtualAddress(page));
+        }
     }
 }
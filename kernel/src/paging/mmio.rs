@@ -0,0 +1,35 @@
+// MEMORY MAPPED I/O WINDOW
+
+use super::{Mapper, PageTableFlags, PhysicalAddress, VirtualAddress, PAGE_SIZE_2M};
+
+const MMIO_BASE: u64 = 0x0000_7F00_0000_0000;
+const MMIO_END:  u64 = 0x0000_7F80_0000_0000;
+
+static mut NEXT: u64 = MMIO_BASE;
+
+//============================================================
+/// Map device registers uncached into the MMIO window
+//
+//============================================================
+pub fn map(physical: PhysicalAddress, size: u64) -> VirtualAddress {
+
+    let offset = physical.0 & 0xfff;
+    let frame  = physical.0 & !0xfff;
+    let pages  = (offset + size + 0xfff) >> 12;
+
+    let page = unsafe {
+        // large apertures (framebuffers) start on a 2 MiB boundary when the frame does, for huge pages
+        if pages << 12 >= PAGE_SIZE_2M && frame % PAGE_SIZE_2M == 0 {
+            NEXT = (NEXT + PAGE_SIZE_2M - 1) & !(PAGE_SIZE_2M - 1);
+        }
+        let page = NEXT;
+        NEXT += pages << 12;
+        assert!(NEXT <= MMIO_END, "MMIO window exhausted");
+        page
+    };
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE;
+    Mapper::new().map_range(page, frame, pages << 12, flags).expect("no frame for the MMIO page tables");
+
+    VirtualAddress(page + offset)
+}
@@ -1,6 +1,13 @@
 use core::fmt;
+use bitflags::bitflags;
+use crate::cpu;
 pub mod mapper;
-pub use mapper::Mapper;
+pub mod mmio;
+pub mod tlb;
+pub use mapper::{MapError, Mapper};
+
+/// All of physical memory is mapped at this offset by the bootloader
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0x18000000000;
 
 #[derive(Clone, Copy)]
 pub struct PhysicalAddress(pub u64);
@@ -14,24 +21,91 @@ pub struct PageTable {
     pub entries: [PageTableEntry; 512],
 }
 
+pub const PAGE_SIZE_4K: u64 = 1 << 12;
+pub const PAGE_SIZE_2M: u64 = 1 << 21;
+pub const PAGE_SIZE_1G: u64 = 1 << 30;
+
+const CPUID_1G_PAGES: u32 = 1 << 26;   // leaf 0x80000001, edx
+
+/// Bits 12-51 of an entry: physical address of the frame or next table
+pub const ADDRESS_MASK: u64 = 0x000fffff_fffff000;
+
+bitflags! {
+    /// Bits of a page table entry outside the address
+    #[repr(transparent)]
+    pub struct PageTableFlags: u64 {
+        const PRESENT       = 1 << 0;
+        const WRITABLE      = 1 << 1;
+        const USER          = 1 << 2;   // reachable from ring 3 (needed at every level)
+        const WRITE_THROUGH = 1 << 3;
+        const NO_CACHE      = 1 << 4;
+        const ACCESSED      = 1 << 5;   // set by the CPU
+        const DIRTY         = 1 << 6;   // set by the CPU on write (leaf only)
+        const HUGE          = 1 << 7;   // 2 MiB (P2) or 1 GiB (P3) page
+        const GLOBAL        = 1 << 8;   // kept across CR3 reloads (leaf only)
+        const BIT_9         = 1 << 9;   // available to the OS
+        const BIT_10        = 1 << 10;  // available to the OS
+        const BIT_11        = 1 << 11;  // available to the OS
+        const NO_EXECUTE    = 1 << 63;  // needs EFER.NXE
+    }
+}
+
 #[repr(transparent)]
 pub struct PageTableEntry {
-    pub entry: u64,
+    entry: u64,
 }
 
 impl PageTableEntry {
 
+    pub const fn is_unused(&self) -> bool {
+        self.entry == 0
+    }
+
+    pub fn set_unused(&mut self) {
+        self.entry = 0;
+    }
+
     pub const fn address(&self) -> PhysicalAddress {
-        PhysicalAddress(self.entry & 0x000fffff_fffff000)
+        PhysicalAddress(self.entry & ADDRESS_MASK)
+    }
+
+    // Frame of a 2 MiB or 1 GiB page: bit 12 of a huge entry is PAT, not address
+    pub const fn huge_address(&self, size: u64) -> PhysicalAddress {
+        PhysicalAddress(self.entry & ADDRESS_MASK & !(size - 1))
+    }
+
+    pub const fn flags(&self) -> PageTableFlags {
+        PageTableFlags::from_bits_truncate(self.entry)
+    }
+
+    pub const fn is_present(&self) -> bool {
+        (self.entry & PageTableFlags::PRESENT.bits()) != 0
     }
 
     pub const fn is_huge(&self) -> bool {
-        (self.entry & 0x80) != 0
+        (self.entry & PageTableFlags::HUGE.bits()) != 0
+    }
+
+    //============================================================
+    //
+    //
+    //============================================================
+    pub fn set(&mut self, address: PhysicalAddress, flags: PageTableFlags) {
+        debug_assert!(address.0 & !ADDRESS_MASK == 0, "unaligned frame {:#x}", address.0);
+        self.entry = address.0 | flags.bits();
+    }
+
+    pub fn set_address(&mut self, address: PhysicalAddress) {
+        self.set(address, self.flags());
+    }
+
+    pub fn set_flags(&mut self, flags: PageTableFlags) {
+        self.set(self.address(), flags);
     }
 }
 
 //============================================================
-//
+/// Walk the active tables, stopping at 1 GiB and 2 MiB pages
 //
 //============================================================
 pub fn translate_addr(address: VirtualAddress) -> Option<PhysicalAddress> {
@@ -40,32 +114,45 @@ pub fn translate_addr(address: VirtualAddress) -> Option<PhysicalAddress> {
     let index3 = (address.0 & 0x0000007FC0000000) >> 30;
     let index2 = (address.0 & 0x000000003FE00000) >> 21;
     let index1 = (address.0 & 0x00000000001FF000) >> 12;
-    let index0 = (address.0 & 0x0000000000000FFF) >> 0;
-
-    let mut cr3 : u64;
-    unsafe { llvm_asm!("movq %cr3, %rax" : "={eax}"(cr3) ::: "volatile"); }
 
-    let table = unsafe { &(*((0x18000000000 + cr3) as *const PageTable)) };
+    let table = unsafe { &(*((PHYSICAL_MEMORY_OFFSET + cpu::read_cr3()) as *const PageTable)) };
     let entry = &table.entries[index4 as usize];
-    if entry.entry==0 { return None; }
+    if !entry.is_present() { return None; }
 
-    let table = unsafe { &(*((0x18000000000 + entry.address().0) as *const PageTable)) };
+    let table = unsafe { &(*((PHYSICAL_MEMORY_OFFSET + entry.address().0) as *const PageTable)) };
     let entry = &table.entries[index3 as usize];
-    if entry.entry==0 { return None; }
+    if !entry.is_present() { return None; }
 
-    let table = unsafe { &(*((0x18000000000 + entry.address().0) as *const PageTable)) };
+    if entry.is_huge() {
+        return Some(huge_frame(entry, address, PAGE_SIZE_1G));
+    }
+
+    let table = unsafe { &(*((PHYSICAL_MEMORY_OFFSET + entry.address().0) as *const PageTable)) };
     let entry = &table.entries[index2 as usize];
-    if entry.entry==0 { return None; }
+    if !entry.is_present() { return None; }
 
     if entry.is_huge() {
-        return Some(PhysicalAddress(entry.address().0 + (index1<<12) + index0))
+        return Some(huge_frame(entry, address, PAGE_SIZE_2M));
     }
 
-    let table = unsafe { &(*((0x18000000000 + entry.address().0) as *const PageTable)) };
+    let table = unsafe { &(*((PHYSICAL_MEMORY_OFFSET + entry.address().0) as *const PageTable)) };
     let entry = &table.entries[index1 as usize];
-    if entry.entry==0 { return None; }
+    if !entry.is_present() { return None; }
+
+    Some(PhysicalAddress(entry.address().0 + (address.0 & (PAGE_SIZE_4K - 1))))
+}
+
+fn huge_frame(entry: &PageTableEntry, address: VirtualAddress, size: u64) -> PhysicalAddress {
+    PhysicalAddress(entry.huge_address(size).0 + (address.0 & (size - 1)))
+}
 
-    Some(PhysicalAddress(entry.address().0 + index0))
+//============================================================
+//
+//
+//============================================================
+pub fn supports_1g_pages() -> bool {
+    let (_, _, _, edx) = cpu::cpuid(0x8000_0001, 0);
+    edx & CPUID_1G_PAGES != 0
 }
 
 impl fmt::Debug for VirtualAddress {
@@ -74,6 +161,15 @@ impl fmt::Debug for VirtualAddress {
     }
 }
 
+impl fmt::Debug for PageTableEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PageTableEntry")
+            .field("address", &self.address())
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
+
 impl fmt::Debug for PhysicalAddress {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "PhysicalAddress({:#x})", self.0)
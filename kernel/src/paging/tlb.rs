@@ -0,0 +1,114 @@
+// TLB SHOOTDOWN
+//
+// A page table change is only complete once no CPU caches the old translation.
+// The initiator flushes its own TLB, then interrupts the other online CPUs and
+// waits until each of them has flushed too.
+
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::cpu;
+use crate::interrupts::apic::{self, DeliveryMode, IpiDestination};
+use crate::interrupts::irq::{self, InterruptFrame, IrqReturn};
+use super::VirtualAddress;
+
+pub const VECTOR: u8 = 0xF0;
+
+const FLUSH_ALL: u64 = !0;
+
+static BUSY:    AtomicBool = AtomicBool::new(false);    // one shootdown at a time
+static ADDRESS: AtomicU64  = AtomicU64::new(0);
+static PENDING: AtomicU64  = AtomicU64::new(0);         // CPUs that still have to flush
+
+//============================================================
+/// Listen for shootdown requests (needs the local APIC)
+//
+//============================================================
+pub fn init() {
+    irq::register_reserved(VECTOR, shootdown_handler, 0);
+}
+
+//============================================================
+/// Invalidate the TLB entry of a page on this CPU
+//
+//============================================================
+pub fn flush(address: VirtualAddress) {
+    unsafe { llvm_asm!("invlpg ($0)" :: "r"(address.0) : "memory" : "volatile"); }
+}
+
+//============================================================
+/// Drop every non-global translation of this CPU
+//
+//============================================================
+pub fn flush_all() {
+    unsafe { llvm_asm!("movq %cr3, %rax; movq %rax, %cr3" ::: "rax", "memory" : "volatile"); }
+}
+
+//============================================================
+/// Invalidate a page on every online CPU
+//
+//============================================================
+pub fn shootdown(address: VirtualAddress) {
+    flush(address);
+    broadcast(address.0);
+}
+
+//============================================================
+/// Flush the whole TLB of every online CPU (large unmaps)
+//
+//============================================================
+pub fn shootdown_all() {
+    flush_all();
+    broadcast(FLUSH_ALL);
+}
+
+fn broadcast(address: u64) {
+
+    if !apic::is_enabled() {
+        return;
+    }
+
+    cpu::without_interrupts(|| {
+        let others = cpu::online_mask() & !(1 << cpu::id());
+        if others == 0 {
+            return;
+        }
+
+        // Interrupts stay off while waiting: serve the other initiators by hand
+        // or two CPUs shooting down at once would wait on each other forever
+        while BUSY.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            acknowledge();
+            spin_loop();
+        }
+
+        ADDRESS.store(address, Ordering::SeqCst);
+        PENDING.store(others, Ordering::SeqCst);
+
+        apic::send_ipi(IpiDestination::AllExcludingSelf, DeliveryMode::Fixed, VECTOR);
+
+        while PENDING.load(Ordering::SeqCst) & others != 0 {
+            spin_loop();
+        }
+
+        BUSY.store(false, Ordering::Release);
+    });
+}
+
+// Flush if this CPU is part of the current shootdown
+fn acknowledge() {
+
+    let me = 1 << cpu::id();
+    if PENDING.load(Ordering::SeqCst) & me == 0 {
+        return;
+    }
+
+    match ADDRESS.load(Ordering::SeqCst) {
+        FLUSH_ALL => flush_all(),
+        address   => flush(VirtualAddress(address)),
+    }
+    PENDING.fetch_and(!me, Ordering::SeqCst);
+}
+
+fn shootdown_handler(_frame: &mut InterruptFrame, _context: usize) -> IrqReturn {
+    acknowledge();
+    IrqReturn::Handled
+}
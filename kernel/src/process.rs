@@ -0,0 +1,76 @@
+// USER PROCESSES
+//
+// There is no program loader yet: nothing calls `set_current`, so the memory
+// syscalls answer NoProcess and user page faults are not resolved until one
+// installs the first process.
+
+use bitflags::bitflags;
+use crate::cpu;
+use crate::memory::Context;
+use crate::sync::IrqSpinlock;
+
+bitflags! {
+    /// Privileges of a process beyond its own address space
+    pub struct Capabilities: u64 {
+        const DEVICE_MEMORY = 1 << 0;   // mmap physical ranges that are not RAM (MMIO)
+    }
+}
+
+struct Process {
+    context:      Context,
+    capabilities: Capabilities,
+}
+
+// The running process
+static CURRENT: IrqSpinlock<Option<Process>> = IrqSpinlock::new(None);
+
+//============================================================
+/// Make a context the running process' address space and load it,
+/// returns the previous one
+//============================================================
+pub fn set_current(context: Context, capabilities: Capabilities) -> Option<Context> {
+
+    let mut current = CURRENT.lock();
+
+    context.activate();
+    current.replace(Process { context, capabilities }).map(|process| process.context)
+}
+
+//============================================================
+/// Run `f` on the current address space, None without a process.
+/// `f` must not touch user memory that may fault (the lock is held).
+//============================================================
+pub fn with_current<R>(f: impl FnOnce(&mut Context) -> R) -> Option<R> {
+    CURRENT.lock().as_mut().map(|process| f(&mut process.context))
+}
+
+//============================================================
+/// True if the current process holds `capability`
+//
+//============================================================
+pub fn has_capability(capability: Capabilities) -> bool {
+    CURRENT.lock().as_ref().map_or(false, |process| process.capabilities.contains(capability))
+}
+
+//============================================================
+/// Print the areas of the current process
+//
+//============================================================
+pub fn dump_areas() {
+    match with_current(|context| context.vmas().dump()) {
+        Some(()) => {}
+        None     => crate::println!("no current process"),
+    }
+}
+
+//============================================================
+/// Terminate the current user process
+//
+//============================================================
+pub fn exit(code: i32) -> ! {
+
+    crate::println!("\n[process exited with code {}]", code);
+
+    // No scheduler yet: nothing else to run
+    cpu::halt()
+}
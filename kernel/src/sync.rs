@@ -0,0 +1,71 @@
+// INTERRUPT-SAFE LOCKING
+//
+// A spinlock taken with interrupts enabled deadlocks as soon as a handler on
+// the same CPU wants it: the handler spins on a lock its interrupted owner can
+// never release. IrqSpinlock disables interrupts for as long as its guard lives.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::cpu;
+
+pub struct IrqSpinlock<T> {
+    locked: AtomicBool,
+    data:   UnsafeCell<T>,
+}
+
+pub struct IrqSpinlockGuard<'a, T> {
+    lock: &'a IrqSpinlock<T>,
+}
+
+unsafe impl<T: Send> Sync for IrqSpinlock<T> {}
+unsafe impl<T: Send> Send for IrqSpinlock<T> {}
+
+impl<T> IrqSpinlock<T> {
+
+    //============================================================
+    //
+    //
+    //============================================================
+    pub const fn new(data: T) -> IrqSpinlock<T> {
+        IrqSpinlock { locked: AtomicBool::new(false), data: UnsafeCell::new(data) }
+    }
+
+    //============================================================
+    /// Disable interrupts (nested per CPU), then spin until acquired
+    //
+    //============================================================
+    pub fn lock(&self) -> IrqSpinlockGuard<T> {
+
+        cpu::push_interrupts_disabled();
+
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+        IrqSpinlockGuard { lock: self }
+    }
+}
+
+impl<'a, T> Deref for IrqSpinlockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for IrqSpinlockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for IrqSpinlockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        cpu::pop_interrupts_disabled();
+    }
+}
@@ -0,0 +1,119 @@
+// FAST SYSCALL ENTRY (syscall/sysret)
+//
+// Same dispatch table and register convention as `int 0x80`, except that the
+// `syscall` instruction clobbers rcx (user rip) and r11 (user rflags): the
+// argument normally passed in rcx is passed in r10 instead.
+
+use core::ptr;
+use crate::cpu::{gdt, msr, tss, Registers};
+
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_IF: u64 = 1 << 9;
+const RFLAGS_DF: u64 = 1 << 10;
+const RFLAGS_AC: u64 = 1 << 18;
+
+//============================================================
+/// Per CPU scratch area reached through `swapgs` on entry
+//
+//============================================================
+#[repr(C)]
+struct SyscallScratch {
+    kernel_stack_slot: *const u64,      // gs:0 - where to find the current kernel stack (TSS.RSP0)
+    user_stack:        u64,             // gs:8 - user rsp while switching stacks
+}
+
+static mut SCRATCH: SyscallScratch = SyscallScratch { kernel_stack_slot: ptr::null(), user_stack: 0 };
+
+//============================================================
+/// User state saved by the `syscall` entry stub
+//
+//============================================================
+#[repr(C)]
+pub struct FastSyscallFrame {
+    pub registers: Registers,   // rcx slot holds r10
+    pub rip:       u64,         // from rcx
+    pub rflags:    u64,         // from r11
+    pub rsp:       u64,
+}
+
+// Interrupts are masked by FMASK until sysret. The kernel stack top is 16 bytes
+// aligned and the stub pushes 18 qwords: RSP is aligned when calling into Rust.
+global_asm!("
+    .global syscall_fast_entry
+    syscall_fast_entry:
+        swapgs
+        movq %rsp, %gs:8
+        movq %gs:0, %rsp
+        movq (%rsp), %rsp
+        pushq %gs:8
+        swapgs
+
+        pushq %r11
+        pushq %rcx
+        pushq %r15
+        pushq %r14
+        pushq %r13
+        pushq %r12
+        pushq %r11
+        pushq %r10
+        pushq %r9
+        pushq %r8
+        pushq %rbp
+        pushq %rdi
+        pushq %rsi
+        pushq %rdx
+        pushq %r10
+        pushq %rbx
+        pushq %rax
+
+        movq %rsp, %rdi
+        call syscall_fast_dispatch
+
+        popq %rax
+        popq %rbx
+        popq %r10
+        popq %rdx
+        popq %rsi
+        popq %rdi
+        popq %rbp
+        popq %r8
+        popq %r9
+        popq %r10
+        popq %r11
+        popq %r12
+        popq %r13
+        popq %r14
+        popq %r15
+        popq %rcx
+        popq %r11
+        popq %rsp
+        sysretq
+");
+
+extern "C" {
+    fn syscall_fast_entry();
+}
+
+//============================================================
+// PER CPU
+//
+//============================================================
+pub fn init() {
+    unsafe {
+        SCRATCH.kernel_stack_slot = tss::kernel_stack_slot();
+
+        let sysret_base = (gdt::USER_DATA_SELECTOR.0 & !0x3) - 8;    // sysret: SS = base+8, CS = base+16
+        let star = ((sysret_base | 3) as u64) << 48 | (gdt::KERNEL_CODE_SELECTOR.0 as u64) << 32;
+
+        msr::write(msr::IA32_STAR, star);
+        msr::write(msr::IA32_LSTAR, syscall_fast_entry as u64);
+        msr::write(msr::IA32_FMASK, RFLAGS_TF | RFLAGS_IF | RFLAGS_DF | RFLAGS_AC);
+        msr::write(msr::IA32_KERNEL_GS_BASE, &SCRATCH as *const _ as u64);
+        msr::write(msr::IA32_EFER, msr::read(msr::IA32_EFER) | msr::EFER_SYSCALL_ENABLE);
+    }
+}
+
+#[no_mangle]
+extern "C" fn syscall_fast_dispatch(frame: &mut FastSyscallFrame) {
+    super::dispatch(&mut frame.registers);
+}
@@ -0,0 +1,266 @@
+pub mod fast;
+
+use core::{mem, slice, str};
+use crate::cpu::Registers;
+use crate::memory::{FrameAllocator, USER_SPACE_START, USER_SPACE_END};
+use crate::memory::fault::{Access, COPY_ON_WRITE};
+use crate::memory::vma::{Backing, Protection, SharedMemory, VmaError};
+use crate::process::{self, Capabilities};
+use crate::heap::{HeapAllocator, HeapStats};
+use crate::paging::{Mapper, PageTableFlags, PAGE_SIZE_4K};
+
+// Syscall numbers (eax)
+pub const SYSCALL_PRINT:        usize = 1;
+pub const SYSCALL_PROCESS_EXIT: usize = 5;
+pub const SYSCALL_MMAP:         usize = 10;
+pub const SYSCALL_MUNMAP:       usize = 11;
+pub const SYSCALL_MPROTECT:     usize = 12;
+pub const SYSCALL_HEAP_STATS:   usize = 13;
+
+// mmap flags (r8)
+pub const MAP_SHARED:   u64 = 1 << 0;   // stays shared with forked children
+pub const MAP_PHYSICAL: u64 = 1 << 1;   // device memory at r9 (Capabilities::DEVICE_MEMORY)
+
+const SYSCALL_COUNT: usize = 64;
+
+pub type SyscallHandler = fn(&Registers) -> Result<u64, SyscallError>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u64)]
+pub enum SyscallError {
+    InvalidSyscall  = 1,
+    InvalidAddress  = 2,
+    InvalidArgument = 3,
+    OutOfMemory     = 4,
+    AddressInUse    = 5,
+    NoProcess       = 6,
+    Corrupted       = 7,
+    NotPermitted    = 8,
+}
+
+static SYSCALLS: [Option<SyscallHandler>; SYSCALL_COUNT] = syscall_table();
+
+const fn syscall_table() -> [Option<SyscallHandler>; SYSCALL_COUNT] {
+    let mut table: [Option<SyscallHandler>; SYSCALL_COUNT] = [None; SYSCALL_COUNT];
+    table[SYSCALL_PRINT]        = Some(sys_print);
+    table[SYSCALL_PROCESS_EXIT] = Some(sys_process_exit);
+    table[SYSCALL_MMAP]         = Some(sys_mmap);
+    table[SYSCALL_MUNMAP]       = Some(sys_munmap);
+    table[SYSCALL_MPROTECT]     = Some(sys_mprotect);
+    table[SYSCALL_HEAP_STATS]   = Some(sys_heap_stats);
+    table
+}
+
+//============================================================
+/// Dispatch the syscall in eax, the result (or -error) is returned in rax
+//
+//============================================================
+pub fn dispatch(registers: &mut Registers) {
+
+    let handler = SYSCALLS.get(registers.rax as usize).copied().flatten();
+
+    let result = match handler {
+        Some(handler) => handler(registers),
+        None          => Err(SyscallError::InvalidSyscall),
+    };
+
+    registers.rax = match result {
+        Ok(value)  => value,
+        Err(error) => -(error as i64) as u64,
+    };
+}
+
+//============================================================
+/// Validate a user buffer and borrow it. Pages of readable areas are fine
+/// even if not populated yet: touching them faults them in.
+//============================================================
+fn user_buffer<'a>(address: u64, len: u64) -> Result<&'a [u8], SyscallError> {
+
+    check_user_range(address, len, Access::Read)?;
+
+    Ok(unsafe { slice::from_raw_parts(address as *const u8, len as usize) })
+}
+
+//============================================================
+/// Same for a buffer the kernel writes to
+//
+//============================================================
+fn user_buffer_mut<'a>(address: u64, len: u64) -> Result<&'a mut [u8], SyscallError> {
+
+    check_user_range(address, len, Access::Write)?;
+
+    Ok(unsafe { slice::from_raw_parts_mut(address as *mut u8, len as usize) })
+}
+
+// Every page either in an area allowing the access or already mapped for it
+fn check_user_range(address: u64, len: u64, access: Access) -> Result<(), SyscallError> {
+
+    let end = address.checked_add(len).ok_or(SyscallError::InvalidAddress)?;
+
+    if address < USER_SPACE_START || end > USER_SPACE_END {
+        return Err(SyscallError::InvalidAddress);
+    }
+
+    let mut page = address & !0xfff;
+    while page < end {
+        let allowed = process::with_current(|context| {
+            context.vmas().find(page).map_or(false, |vma| vma.allows(access))
+        });
+        if allowed != Some(true) && !is_mapped(page, access) {
+            return Err(SyscallError::InvalidAddress);
+        }
+        page += 4096;
+    }
+    Ok(())
+}
+
+// Present user page (the boot identity mappings are not), writable or
+// copy-on-write for a write
+fn is_mapped(page: u64, access: Access) -> bool {
+
+    match Mapper::new().entry_mut(page) {
+        Some(entry) if entry.is_present() => {
+            let flags = entry.flags();
+            flags.contains(PageTableFlags::USER)
+                && (access != Access::Write || flags.intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE))
+        }
+        _ => false,
+    }
+}
+
+//============================================================
+/// print(rsi: string, ecx: length) -> length
+//
+//============================================================
+fn sys_print(registers: &Registers) -> Result<u64, SyscallError> {
+
+    let len    = registers.rcx & 0xffff_ffff;
+    let buffer = user_buffer(registers.rsi, len)?;
+    let string = str::from_utf8(buffer).map_err(|_| SyscallError::InvalidArgument)?;
+
+    crate::print!("{}", string);
+
+    Ok(len)
+}
+
+//============================================================
+/// process_exit(ecx: code) -> !
+//
+//============================================================
+fn sys_process_exit(registers: &Registers) -> Result<u64, SyscallError> {
+
+    process::exit(registers.rcx as u32 as i32)
+}
+
+//============================================================
+/// mmap(rsi: address or 0, rcx: length, rdx: protection, r8: flags,
+///      r9: physical address) -> address
+//============================================================
+fn sys_mmap(registers: &Registers) -> Result<u64, SyscallError> {
+
+    let address    = registers.rsi;
+    let length     = registers.rcx;
+    let protection = Protection::from_bits(registers.rdx).ok_or(SyscallError::InvalidArgument)?;
+    let flags      = registers.r8;
+
+    if flags & !(MAP_SHARED | MAP_PHYSICAL) != 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    // range first: the backing is sized by it
+    let length = length.checked_add(PAGE_SIZE_4K - 1).ok_or(SyscallError::InvalidArgument)? & !(PAGE_SIZE_4K - 1);
+    if length == 0 || length > USER_SPACE_END - USER_SPACE_START {
+        return Err(SyscallError::InvalidArgument);
+    }
+    if address != 0 && (address % PAGE_SIZE_4K != 0 || address < USER_SPACE_START || address > USER_SPACE_END - length) {
+        return Err(SyscallError::InvalidAddress);
+    }
+
+    if flags & MAP_PHYSICAL != 0 && !process::has_capability(Capabilities::DEVICE_MEMORY) {
+        return Err(SyscallError::NotPermitted);
+    }
+
+    let backing = match (flags & MAP_SHARED != 0, flags & MAP_PHYSICAL != 0) {
+        (false, false) => Backing::Anonymous,
+        (true,  false) => Backing::Shared { object: SharedMemory::new(length), offset: 0 },
+        (false, true)  => Backing::Physical(device_memory(registers.r9, length)?),
+        (true,  true)  => return Err(SyscallError::InvalidArgument),
+    };
+
+    let address = if address == 0 { None } else { Some(address) };
+
+    process::with_current(|context| context.mmap(address, length, protection, backing))
+        .ok_or(SyscallError::NoProcess)?
+        .map_err(SyscallError::from)
+}
+
+//============================================================
+/// munmap(rsi: address, rcx: length) -> 0
+//
+//============================================================
+fn sys_munmap(registers: &Registers) -> Result<u64, SyscallError> {
+
+    process::with_current(|context| context.munmap(registers.rsi, registers.rcx))
+        .ok_or(SyscallError::NoProcess)?
+        .map(|_| 0)
+        .map_err(SyscallError::from)
+}
+
+//============================================================
+/// mprotect(rsi: address, rcx: length, rdx: protection) -> 0
+//
+//============================================================
+fn sys_mprotect(registers: &Registers) -> Result<u64, SyscallError> {
+
+    let protection = Protection::from_bits(registers.rdx).ok_or(SyscallError::InvalidArgument)?;
+
+    process::with_current(|context| context.mprotect(registers.rsi, registers.rcx, protection))
+        .ok_or(SyscallError::NoProcess)?
+        .map(|_| 0)
+        .map_err(SyscallError::from)
+}
+
+//============================================================
+/// heap_stats(rsi: buffer, rcx: length) -> bytes written (kernel HeapStats)
+//
+//============================================================
+fn sys_heap_stats(registers: &Registers) -> Result<u64, SyscallError> {
+
+    let size = mem::size_of::<HeapStats>() as u64;
+    if registers.rcx < size {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    // snapshot first: the copy may fault pages in
+    let stats  = HeapAllocator::stats().map_err(|_| SyscallError::Corrupted)?;
+    let buffer = user_buffer_mut(registers.rsi, size)?;
+
+    let bytes = unsafe { slice::from_raw_parts(&stats as *const HeapStats as *const u8, size as usize) };
+    buffer.copy_from_slice(bytes);
+
+    Ok(size)
+}
+
+// Page aligned physical range that is not RAM
+fn device_memory(address: u64, length: u64) -> Result<u64, SyscallError> {
+
+    let end = address.checked_add(length).ok_or(SyscallError::InvalidArgument)?;
+    if address % PAGE_SIZE_4K != 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    if FrameAllocator::overlaps_ram(address, end) {
+        return Err(SyscallError::InvalidAddress);
+    }
+    Ok(address)
+}
+
+impl From<VmaError> for SyscallError {
+    fn from(error: VmaError) -> SyscallError {
+        match error {
+            VmaError::InvalidRange => SyscallError::InvalidArgument,
+            VmaError::Overlap      => SyscallError::AddressInUse,
+            VmaError::NoSpace      => SyscallError::OutOfMemory,
+        }
+    }
+}
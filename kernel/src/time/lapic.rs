@@ -0,0 +1,39 @@
+// LOCAL APIC TIMER
+
+use crate::interrupts::apic;
+
+pub const VECTOR: u8 = 0x40;
+
+const LVT_PERIODIC: u32 = 1 << 17;
+const LVT_MASKED:   u32 = 1 << 16;
+const DIVIDE_BY_16: u32 = 0b0011;
+
+//============================================================
+/// Start counting down from the maximum, masked (calibration)
+//
+//============================================================
+pub fn start_calibration() {
+    apic::write(apic::REG_TIMER_DIVIDE, DIVIDE_BY_16);
+    apic::write(apic::REG_LVT_TIMER, LVT_MASKED | VECTOR as u32);
+    apic::write(apic::REG_TIMER_INITIAL, u32::MAX);
+}
+
+//============================================================
+/// Counts elapsed since `start_calibration`
+//
+//============================================================
+pub fn stop_calibration() -> u32 {
+    let elapsed = u32::MAX - apic::read(apic::REG_TIMER_CURRENT);
+    apic::write(apic::REG_TIMER_INITIAL, 0);
+    elapsed
+}
+
+//============================================================
+/// Fire `VECTOR` every `counts` (as measured by the calibration)
+//
+//============================================================
+pub fn start(counts: u32) {
+    apic::write(apic::REG_TIMER_DIVIDE, DIVIDE_BY_16);
+    apic::write(apic::REG_LVT_TIMER, LVT_PERIODIC | VECTOR as u32);
+    apic::write(apic::REG_TIMER_INITIAL, counts.max(1));
+}
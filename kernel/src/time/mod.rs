@@ -0,0 +1,155 @@
+// SYSTEM TIME
+//
+// A periodic tick (PIT on IRQ0, then the local APIC timer once calibrated)
+// drives a monotonic clock, the pending timeouts and deferred heap releases.
+
+pub mod pit;
+pub mod lapic;
+pub mod timeout;
+
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use crate::cpu;
+use crate::interrupts::{apic, irq};
+use crate::interrupts::irq::{HandlerId, InterruptFrame, IrqReturn};
+
+pub const TICK_HZ: u64 = 1000;
+
+const CALIBRATION_TICKS: u64 = 50;
+
+static TICKS:     AtomicU64 = AtomicU64::new(0);
+static NANOS:     AtomicU64 = AtomicU64::new(0);
+static PERIOD_NS: AtomicU64 = AtomicU64::new(0);
+
+//============================================================
+/// Monotonic point in time (nanoseconds since the timer started)
+//
+//============================================================
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+
+    pub fn now() -> Instant {
+        Instant(NANOS.load(Ordering::Relaxed))
+    }
+
+    pub const fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now() - *self
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0.saturating_add(duration.as_nanos() as u64))
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+    fn sub(self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+}
+
+//============================================================
+/// Start the PIT, then move to the local APIC timer when enabled
+//  (interrupts must be enabled)
+//============================================================
+pub fn init() {
+
+    PERIOD_NS.store(pit::start(TICK_HZ), Ordering::Relaxed);
+    let pit_handler = irq::register_irq(irq::line_vector(pit::IRQ), tick_handler, 0).expect("PIT vector refused");
+
+    if apic::is_enabled() {
+        switch_to_lapic_timer(pit_handler);
+    }
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn uptime() -> Duration {
+    Duration::from_nanos(Instant::now().as_nanos())
+}
+
+//============================================================
+/// Halt until the duration elapsed (interrupts must be enabled)
+//
+//============================================================
+pub fn sleep_for(duration: Duration) {
+    sleep_until(Instant::now() + duration);
+}
+
+//============================================================
+//
+//
+//============================================================
+pub fn sleep_until(deadline: Instant) {
+    debug_assert!(cpu::interrupts_enabled());
+    while Instant::now() < deadline {
+        cpu::wait_for_interrupt();
+    }
+}
+
+fn tick() {
+    let period = PERIOD_NS.load(Ordering::Relaxed);
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    let now = NANOS.fetch_add(period, Ordering::Relaxed) + period;
+    timeout::run_expired(Instant(now));
+
+    // no lock held here: heap tails can be unmapped (shootdown)
+    crate::heap::HeapAllocator::release_deferred();
+}
+
+//============================================================
+/// Measure the LAPIC timer against the PIT, then hand the tick over
+//
+//============================================================
+fn switch_to_lapic_timer(pit_handler: HandlerId) {
+
+    let start = ticks();
+    while ticks() == start {
+        cpu::wait_for_interrupt();          // start on a tick edge
+    }
+
+    lapic::start_calibration();
+    let start = ticks();
+    while ticks() < start + CALIBRATION_TICKS {
+        cpu::wait_for_interrupt();
+    }
+    let counts = lapic::stop_calibration() as u64;
+
+    let elapsed_ns = CALIBRATION_TICKS * PERIOD_NS.load(Ordering::Relaxed);
+    let period_ns  = 1_000_000_000 / TICK_HZ;
+    let per_tick   = counts * period_ns / elapsed_ns;
+
+    cpu::without_interrupts(|| {
+        irq::unregister_irq(irq::line_vector(pit::IRQ), pit_handler);
+        irq::register_irq(lapic::VECTOR, tick_handler, 0).expect("local APIC timer vector refused");
+        PERIOD_NS.store(period_ns, Ordering::Relaxed);
+        lapic::start(per_tick as u32);
+    });
+
+    crate::println!("LAPIC timer: {} counts per {} ns tick", per_tick, period_ns);
+}
+
+fn tick_handler(_frame: &mut InterruptFrame, _context: usize) -> IrqReturn {
+    tick();
+    IrqReturn::Handled
+}
@@ -0,0 +1,31 @@
+// 8253/8254 PROGRAMMABLE INTERVAL TIMER
+
+use crate::cpu::io::outb;
+
+pub const IRQ: u8 = 0;
+
+pub const FREQUENCY: u64 = 1_193_182;      // Hz
+
+const CHANNEL0: u16 = 0x40;
+const COMMAND:  u16 = 0x43;
+
+const SELECT_CHANNEL0: u8 = 0b00 << 6;
+const ACCESS_LOW_HIGH: u8 = 0b11 << 4;
+const MODE_RATE:       u8 = 0b010 << 1;    // mode 2: rate generator
+
+//============================================================
+/// Fire IRQ0 periodically at (about) `hz`, returns the exact period in ns
+//
+//============================================================
+pub fn start(hz: u64) -> u64 {
+
+    let divisor = (FREQUENCY / hz).max(1).min(0xffff) as u16;
+
+    unsafe {
+        outb(COMMAND, SELECT_CHANNEL0 | ACCESS_LOW_HIGH | MODE_RATE);
+        outb(CHANNEL0, divisor as u8);
+        outb(CHANNEL0, (divisor >> 8) as u8);
+    }
+
+    divisor as u64 * 1_000_000_000 / FREQUENCY
+}
@@ -0,0 +1,99 @@
+// PENDING TIMEOUTS (min-heap ordered by deadline)
+
+use alloc::collections::BinaryHeap;
+use core::cmp::{Ordering, Reverse};
+use lazy_static::lazy_static;
+use crate::sync::IrqSpinlock;
+use super::Instant;
+
+pub type TimeoutCallback = fn(context: usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeoutId(u64);
+
+struct Timeout {
+    deadline: Instant,
+    id:       TimeoutId,
+    callback: TimeoutCallback,
+    context:  usize,
+}
+
+struct Queue {
+    pending: BinaryHeap<Reverse<Timeout>>,
+    next_id: u64,
+}
+
+lazy_static! {
+    static ref QUEUE: IrqSpinlock<Queue> = IrqSpinlock::new(Queue {
+        pending: BinaryHeap::new(),
+        next_id: 0,
+    });
+}
+
+//============================================================
+/// Call `callback(context)` from the timer interrupt once `deadline` is reached
+//
+//============================================================
+pub fn add(deadline: Instant, callback: TimeoutCallback, context: usize) -> TimeoutId {
+    let mut queue = QUEUE.lock();
+    let id = TimeoutId(queue.next_id);
+    queue.next_id += 1;
+    queue.pending.push(Reverse(Timeout { deadline, id, callback, context }));
+    id
+}
+
+//============================================================
+/// Returns false if the timeout already fired (or never existed)
+//
+//============================================================
+pub fn cancel(id: TimeoutId) -> bool {
+    let mut queue = QUEUE.lock();
+    let before = queue.pending.len();
+
+    let pending = core::mem::replace(&mut queue.pending, BinaryHeap::new()).into_vec();
+    queue.pending = pending.into_iter().filter(|timeout| timeout.0.id != id).collect();
+
+    queue.pending.len() != before
+}
+
+//============================================================
+/// Fire every expired timeout (timer interrupt context)
+//
+//============================================================
+pub fn run_expired(now: Instant) {
+    loop {
+        // the lock is released before calling back, callbacks may add timeouts
+        let expired = {
+            let mut queue = QUEUE.lock();
+            match queue.pending.peek() {
+                Some(timeout) if timeout.0.deadline <= now => queue.pending.pop(),
+                _ => None,
+            }
+        };
+
+        match expired {
+            Some(Reverse(timeout)) => (timeout.callback)(timeout.context),
+            None => break,
+        }
+    }
+}
+
+impl PartialEq for Timeout {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Timeout {}
+
+impl PartialOrd for Timeout {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timeout {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.deadline, self.id).cmp(&(other.deadline, other.id))
+    }
+}